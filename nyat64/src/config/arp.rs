@@ -1,81 +1,852 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::hash::{BuildHasher, Hasher};
+use std::io::ErrorKind;
 use std::net::Ipv4Addr;
+use std::result::Result as StdResult;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use afpacket::r#async::RawPacketStream;
 use anyhow::{bail, Context, Result};
 use async_std::prelude::*;
 use async_std::sync::Mutex;
-use cached::{Cached, TimedCache};
 use log::*;
 use pnet::packet::arp::{Arp, ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
 use pnet::packet::ethernet::{EtherTypes, Ethernet, MutableEthernetPacket};
 use pnet::packet::Packet;
 use pnet::util::MacAddr;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::config::batch::BatchWriter;
+use crate::config::icmp;
+use crate::config::ratelimit::RateLimit;
+use crate::config::stats::STATS;
+use crate::config::stream::Ipv4Stream;
+use crate::config::vlan::{Vlan, VlanAddr};
 use crate::config::MapResult;
 
-type ArpTimedCache = TimedCache<Ipv4Addr, MacAddr>;
+/// Upper bound for the time between requests with backoff
+const MAX_RETRANS_TIME: Duration = Duration::from_secs(60);
 
-#[derive(Debug, Clone)]
+// RFC 5227 timings
+const PROBE_WAIT: Duration = Duration::from_secs(1);
+const PROBE_MIN: Duration = Duration::from_secs(1);
+const PROBE_MAX: Duration = Duration::from_secs(2);
+const ANNOUNCE_WAIT: Duration = Duration::from_secs(2);
+const DEFEND_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ArpConfig {
+	/// Frames queued per unresolved neighbor, the oldest are dropped when it is full
+	pub unres_qlen: usize,
+	/// Time in ms between requests
+	pub retrans_time: u64,
+	/// Factor the time between requests grows by after every unanswered request
+	pub backoff: u32,
+	/// Broadcast requests for an unresolved neighbor
+	pub mcast_probes: u32,
+	/// Unicast requests to confirm a stale neighbor
+	pub ucast_probes: u32,
+	/// Time in s a neighbor is reachable after a reply, it is probed on the next use afterwards
+	pub reachable_time: u64,
+	/// Time in s frames to a neighbor which did not reply are dropped without a new request
+	pub failed_time: u64,
+	/// ICMPv6 Address Unreachable messages per second for dropped frames, 0 disables them
+	pub unreachable_rate: u32,
+	/// Probes for a served address before it is answered for, 0 serves it right away
+	pub dad_probes: u32,
+	/// Gratuitous ARP sent for each served address at startup and on changes
+	pub announce: u32,
+	/// Time in ms between gratuitous ARP
+	pub announce_interval: u64,
+	/// Time in s a stale neighbor is kept without being confirmed
+	pub lifetime: u64,
+	/// Maximum number of learned neighbors, unused ones are evicted first
+	pub size: usize,
+	/// Proxy arp replies per second for the `ipv4_prefix` of the mappings, 0 disables them
+	pub proxy_rate: u32,
+	/// File the resolved neighbors are saved to on shutdown and loaded from at startup
+	pub persist: Option<String>,
+	/// Neighbors with a fixed address, e.g. a gateway that does not answer requests
+	#[serde(rename = "static")]
+	pub static_neighbors: Vec<StaticNeighbor>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct StaticNeighbor {
+	pub address: Ipv4Addr,
+	#[serde(deserialize_with = "mac_from_str")]
+	pub mac: MacAddr,
+	#[serde(flatten)]
+	pub vlan: Vlan,
+}
+
+impl Default for ArpConfig {
+	fn default() -> Self {
+		Self {
+			unres_qlen: 101,
+			retrans_time: 1000,
+			backoff: 1,
+			mcast_probes: 3,
+			ucast_probes: 3,
+			reachable_time: 30,
+			failed_time: 5,
+			unreachable_rate: 100,
+			dad_probes: 3,
+			announce: 3,
+			announce_interval: 1000,
+			lifetime: 300,
+			size: 1024,
+			proxy_rate: 100,
+			persist: None,
+			static_neighbors: Vec::new(),
+		}
+	}
+}
+
+impl ArpConfig {
+	/// Time to wait for a reply after the `probes`th request
+	fn retrans_interval(&self, probes: u32) -> Duration {
+		let factor = self.backoff.max(1).saturating_pow(probes.saturating_sub(1));
+		Duration::from_millis(self.retrans_time.saturating_mul(factor as u64)).min(MAX_RETRANS_TIME)
+	}
+}
+
+/// Duplicate address detection states of a served address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClaimState {
+	/// Probes are sent, requests for it are not answered yet
+	Probing,
+	/// No other host uses it, requests are answered
+	Bound,
+	/// Another host uses it, it is not served
+	Conflict,
+}
+
+#[derive(Debug)]
+struct Claim {
+	state: ClaimState,
+	/// Last gratuitous ARP sent to defend the address
+	defended: Option<Instant>,
+}
+
+impl Claim {
+	/// Handle another host using the address, returns true if it should be defended
+	fn conflict(&mut self, addr: VlanAddr, mac: MacAddr) -> bool {
+		match self.state {
+			ClaimState::Probing => {
+				error!(
+					"arp: {} is already used by {}, not serving it until the next reload",
+					addr, mac
+				);
+			}
+			ClaimState::Bound => match self.defended {
+				Some(defended) if defended.elapsed() < DEFEND_INTERVAL => {
+					error!(
+						"arp: {} is still used by {}, not serving it until the next reload",
+						addr, mac
+					);
+				}
+				_ => {
+					warn!("arp: {} is used by {}, defending it", addr, mac);
+					self.defended = Some(Instant::now());
+					return true;
+				}
+			},
+			ClaimState::Conflict => return false,
+		}
+		STATS.arp_conflicts.inc();
+		self.state = ClaimState::Conflict;
+		false
+	}
+}
+
+/// Neighbor unreachability detection states, like the kernel's
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NeighState {
+	/// Request sent, frames are queued until the reply
+	Incomplete,
+	/// Confirmed within `reachable_time`
+	Reachable,
+	/// Confirmation is too old, it is probed on the next use
+	Stale,
+	/// Still used while unicast requests confirm the address
+	Probe,
+	/// No reply, frames are dropped until `failed_time` passed
+	Failed,
+	/// Configured statically, never expires
+	Permanent,
+}
+
+/// Neighbor as dumped at runtime and persisted across restarts
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NeighborInfo {
+	pub address: Ipv4Addr,
+	#[serde(flatten)]
+	pub vlan: Vlan,
+	#[serde(serialize_with = "mac_to_str", deserialize_with = "mac_from_str")]
+	pub mac: MacAddr,
+	pub state: NeighState,
+	/// Time in s since the last confirmation
+	pub age: u64,
+}
+
+impl Display for NeighborInfo {
+	fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+		write!(
+			f,
+			"{} at {} {:?}, {}s ago",
+			VlanAddr::new(self.vlan, self.address),
+			self.mac,
+			self.state,
+			self.age
+		)
+	}
+}
+
+/// Contents of the file the cache is persisted to
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedCache {
+	/// Unix time in s the cache was saved at
+	saved: u64,
+	neighbors: Vec<NeighborInfo>,
+}
+
+#[derive(Debug)]
+struct Neighbor {
+	state: NeighState,
+	mac: MacAddr,
+	/// Last confirmation, or the failure
+	updated: Instant,
+	/// Our address to send requests from
+	src_addr: Ipv4Addr,
+	/// Requests sent in the current state
+	probes: u32,
+	/// Generation of the running timer, older timers stop
+	timer: u64,
+	queue: VecDeque<Queued>,
+}
+
+/// Frame waiting for resolution, with the ipv6 packet it was translated from
+#[derive(Debug)]
+struct Queued {
+	frame: Vec<u8>,
+	origin: Vec<u8>,
+}
+
+impl Neighbor {
+	fn new(src_addr: Ipv4Addr) -> Self {
+		Self {
+			state: NeighState::Incomplete,
+			mac: MacAddr::zero(),
+			updated: Instant::now(),
+			src_addr,
+			probes: 0,
+			timer: 0,
+			queue: VecDeque::new(),
+		}
+	}
+
+	/// Advance time based states
+	fn expire(&mut self, config: &ArpConfig) {
+		let age = self.updated.elapsed();
+		match self.state {
+			NeighState::Reachable if age >= Duration::from_secs(config.reachable_time) => {
+				self.state = NeighState::Stale;
+			}
+			NeighState::Failed if age >= Duration::from_secs(config.failed_time) => self.reset(),
+			_ => (),
+		}
+		if self.state == NeighState::Stale && age >= Duration::from_secs(config.lifetime) {
+			self.reset();
+		}
+	}
+
+	/// Forget the address, the timer generation is kept so old timers still stop
+	fn reset(&mut self) {
+		self.state = NeighState::Incomplete;
+		self.mac = MacAddr::zero();
+		self.updated = Instant::now();
+		self.probes = 0;
+		self.timer += 1;
+	}
+
+	/// Order in which unused neighbors are evicted, `None` if it must be kept
+	fn eviction_rank(&self) -> Option<(u8, Instant)> {
+		let rank = match self.state {
+			NeighState::Failed => 0,
+			NeighState::Stale => 1,
+			NeighState::Reachable => 2,
+			// reset after expiry, nothing is waiting for it
+			NeighState::Incomplete if self.probes == 0 => 0,
+			NeighState::Incomplete | NeighState::Probe | NeighState::Permanent => return None,
+		};
+		Some((rank, self.updated))
+	}
+
+	fn start_timer(&mut self) -> u64 {
+		self.timer += 1;
+		self.timer
+	}
+
+	/// A request was sent and the answer is still missing
+	fn is_outstanding(&self) -> bool {
+		match self.state {
+			NeighState::Probe => true,
+			NeighState::Incomplete => self.probes > 0,
+			_ => false,
+		}
+	}
+
+	/// Give up the resolution, returns the frames which were waiting for it
+	fn fail(&mut self) -> VecDeque<Queued> {
+		self.state = NeighState::Failed;
+		self.mac = MacAddr::zero();
+		self.updated = Instant::now();
+		self.probes = 0;
+		self.timer += 1;
+		std::mem::take(&mut self.queue)
+	}
+}
+
+/// Neighbors on the ipv4 interface, frames to unresolved neighbors are queued until the reply
+/// arrives
+#[derive(Clone)]
 pub struct ArpCache {
-	cache: Arc<Mutex<ArpTimedCache>>,
+	neighbors: Arc<Mutex<HashMap<VlanAddr, Neighbor>>>,
+	config: Arc<ArpConfig>,
+	stream: Ipv4Stream,
+	egress: BatchWriter,
+	tun_egress: BatchWriter,
+	if_mac: Arc<std::sync::RwLock<MacAddr>>,
+	/// Answer requests for and announce the `ipv4_local` addresses
+	serve: bool,
+	/// Number of static entries, they do not count against `size`
+	permanent: usize,
+	unreachable_limit: Arc<std::sync::Mutex<RateLimit>>,
+	proxy_limit: Arc<std::sync::Mutex<RateLimit>>,
+	/// Served addresses and whether another host uses them
+	claims: Arc<std::sync::Mutex<HashMap<VlanAddr, Claim>>>,
 }
 
 impl ArpCache {
-	pub fn new() -> Self {
-		let cache = Arc::new(Mutex::new(TimedCache::with_lifespan(300)));
-		Self { cache }
+	pub fn new(
+		config: &ArpConfig,
+		stream: Ipv4Stream,
+		egress: BatchWriter,
+		tun_egress: BatchWriter,
+		if_mac: MacAddr,
+		serve: bool,
+	) -> Self {
+		let limit = RateLimit::new(config.unreachable_rate, config.unreachable_rate);
+		let proxy_limit = RateLimit::new(config.proxy_rate, config.proxy_rate);
+
+		let mut neighbors = HashMap::new();
+		for neighbor in &config.static_neighbors {
+			let addr = VlanAddr::new(neighbor.vlan, neighbor.address);
+			debug!("arp: static neighbor {} at {}", addr, neighbor.mac);
+			let mut entry = Neighbor::new(Ipv4Addr::UNSPECIFIED);
+			entry.state = NeighState::Permanent;
+			entry.mac = neighbor.mac;
+			neighbors.insert(addr, entry);
+		}
+
+		Self {
+			permanent: neighbors.len(),
+			neighbors: Arc::new(Mutex::new(neighbors)),
+			config: Arc::new(config.clone()),
+			stream,
+			egress,
+			tun_egress,
+			if_mac: Arc::new(std::sync::RwLock::new(if_mac)),
+			serve,
+			unreachable_limit: Arc::new(std::sync::Mutex::new(limit)),
+			proxy_limit: Arc::new(std::sync::Mutex::new(proxy_limit)),
+			claims: Default::default(),
+		}
+	}
+
+	/// Hardware address of the ipv4 interface
+	pub fn mac(&self) -> MacAddr {
+		*self.if_mac.read().unwrap()
+	}
+
+	/// Update the hardware address after it changed on the interface
+	pub fn set_mac(&self, mac: MacAddr) {
+		*self.if_mac.write().unwrap() = mac;
+	}
+
+	/// Snapshot of all neighbors, ordered by address
+	pub async fn dump(&self) -> Vec<NeighborInfo> {
+		let neighbors = self.neighbors.lock().await;
+		let mut dump: Vec<NeighborInfo> = neighbors
+			.iter()
+			.map(|(addr, entry)| NeighborInfo {
+				address: addr.addr,
+				vlan: addr.vlan,
+				mac: entry.mac,
+				state: entry.state,
+				age: entry.updated.elapsed().as_secs(),
+			})
+			.collect();
+		dump.sort_by_key(|neighbor| (neighbor.address, neighbor.vlan));
+		dump
+	}
+
+	/// Write the resolved neighbors to `path`, so they are known right away after a restart
+	pub async fn save(&self, path: &str) -> Result<()> {
+		let neighbors: Vec<NeighborInfo> = self
+			.dump()
+			.await
+			.into_iter()
+			.filter(|neighbor| {
+				matches!(
+					neighbor.state,
+					NeighState::Reachable | NeighState::Stale | NeighState::Probe
+				)
+			})
+			.collect();
+		let count = neighbors.len();
+		let saved = SavedCache {
+			saved: unix_time(),
+			neighbors,
+		};
+
+		let json = serde_json::to_string_pretty(&saved).context("Serialize arp cache")?;
+		// a crash while writing must not leave a truncated cache behind
+		let tmp = format!("{}.tmp", path);
+		async_std::fs::write(&tmp, json)
+			.await
+			.with_context(|| format!("Write arp cache to {}", tmp))?;
+		async_std::fs::rename(&tmp, path)
+			.await
+			.with_context(|| format!("Rename {} to {}", tmp, path))?;
+		debug!("saved {} neighbors to {}", count, path);
+
+		Ok(())
+	}
+
+	/// Restore the neighbors saved by [`save`](Self::save), they are stale and confirmed on their
+	/// next use. Neighbors older than `lifetime` are skipped.
+	pub async fn load(&self, path: &str) -> Result<()> {
+		let json = match async_std::fs::read_to_string(path).await {
+			Ok(json) => json,
+			Err(e) if e.kind() == ErrorKind::NotFound => {
+				debug!("no arp cache saved at {}", path);
+				return Ok(());
+			}
+			Err(e) => return Err(e).with_context(|| format!("Read arp cache from {}", path)),
+		};
+		let saved: SavedCache = serde_json::from_str(&json).context("Parse arp cache")?;
+		let downtime = unix_time().saturating_sub(saved.saved);
+
+		let mut neighbors = self.neighbors.lock().await;
+		let mut loaded = 0;
+		for neighbor in saved.neighbors {
+			let addr = VlanAddr::new(neighbor.vlan, neighbor.address);
+			let age = neighbor.age.saturating_add(downtime);
+			if age >= self.config.lifetime
+				|| neighbors.contains_key(&addr)
+				|| !self.make_room(&mut neighbors)
+			{
+				continue;
+			}
+
+			let mut entry = Neighbor::new(Ipv4Addr::UNSPECIFIED);
+			entry.state = NeighState::Stale;
+			entry.mac = neighbor.mac;
+			entry.updated = Instant::now()
+				.checked_sub(Duration::from_secs(age))
+				.unwrap_or_else(Instant::now);
+			neighbors.insert(addr, entry);
+			loaded += 1;
+		}
+		debug!("loaded {} neighbors from {}", loaded, path);
+
+		Ok(())
+	}
+
+	/// Probe for `addrs` and serve the ones no other host uses, then announce them (RFC 5227).
+	///
+	/// Does nothing unless the `ipv4_local` addresses are served by nyat64.
+	pub async fn claim(&self, addrs: &[VlanAddr]) {
+		if !self.serve || addrs.is_empty() {
+			return;
+		}
+
+		{
+			let mut claims = self.claims.lock().unwrap();
+			for addr in addrs {
+				let claim = Claim {
+					state: ClaimState::Probing,
+					defended: None,
+				};
+				claims.insert(*addr, claim);
+			}
+		}
+
+		if self.config.dad_probes != 0 {
+			async_std::task::sleep(jitter(PROBE_WAIT)).await;
+			for i in 0..self.config.dad_probes {
+				if i != 0 {
+					async_std::task::sleep(PROBE_MIN + jitter(PROBE_MAX - PROBE_MIN)).await;
+				}
+
+				let mac = self.mac();
+				for addr in self.claimed(addrs, ClaimState::Probing) {
+					trace!("arp: probing for {}", addr);
+					let packet = Self::create_request(
+						Ipv4Addr::UNSPECIFIED,
+						addr.addr,
+						addr.vlan,
+						mac,
+						None,
+					);
+					if let Err(e) = self.stream.clone().write_all(&packet).await {
+						warn!("failed to probe for {}: {}", addr, e);
+					}
+				}
+			}
+			async_std::task::sleep(ANNOUNCE_WAIT).await;
+		}
+
+		let bound: Vec<VlanAddr> = {
+			let mut claims = self.claims.lock().unwrap();
+			addrs
+				.iter()
+				.copied()
+				.filter(|addr| match claims.get_mut(addr) {
+					Some(claim) if claim.state == ClaimState::Probing => {
+						claim.state = ClaimState::Bound;
+						true
+					}
+					_ => false,
+				})
+				.collect()
+		};
+		for addr in &bound {
+			debug!("arp: serving {}", addr);
+		}
+		self.announce(&bound).await;
+	}
+
+	/// Stop serving `addrs`, e.g. after their mappings were removed
+	pub fn release(&self, addrs: &[VlanAddr]) {
+		let mut claims = self.claims.lock().unwrap();
+		for addr in addrs {
+			claims.remove(addr);
+		}
+	}
+
+	/// Whether requests for `addr` are answered
+	pub fn serves(&self, addr: VlanAddr) -> bool {
+		!self.claimed(&[addr], ClaimState::Bound).is_empty()
+	}
+
+	/// The `addrs` given up because another host uses them
+	pub fn conflicts(&self, addrs: &[VlanAddr]) -> Vec<VlanAddr> {
+		self.claimed(addrs, ClaimState::Conflict)
+	}
+
+	/// Whether frames may be sent from `addr`, not while it is probed for or used by another
+	/// host. Addresses which are not claimed, e.g. without `send_arp`, are always usable.
+	fn usable(&self, addr: VlanAddr) -> bool {
+		match self.claims.lock().unwrap().get(&addr) {
+			Some(claim) => claim.state == ClaimState::Bound,
+			None => true,
+		}
+	}
+
+	/// The `addrs` currently in `state`
+	fn claimed(&self, addrs: &[VlanAddr], state: ClaimState) -> Vec<VlanAddr> {
+		let claims = self.claims.lock().unwrap();
+		addrs
+			.iter()
+			.copied()
+			.filter(|addr| claims.get(addr).map(|claim| claim.state) == Some(state))
+			.collect()
 	}
 
-	pub async fn request(
+	/// Send gratuitous ARP for the served `addrs`, so neighbors update their caches to our
+	/// address
+	pub async fn announce(&self, addrs: &[VlanAddr]) {
+		if !self.serve || addrs.is_empty() {
+			return;
+		}
+
+		for i in 0..self.config.announce {
+			if i != 0 {
+				async_std::task::sleep(Duration::from_millis(self.config.announce_interval)).await;
+			}
+
+			// reread every round, the address might change while announcing
+			let mac = self.mac();
+			for addr in self.claimed(addrs, ClaimState::Bound) {
+				trace!("arp: announcing {} at {}", addr, mac);
+				let packet = Self::create_request(addr.addr, addr.addr, addr.vlan, mac, None);
+				if let Err(e) = self.stream.clone().write_all(&packet).await {
+					warn!("failed to announce {}: {}", addr, e);
+					return;
+				}
+			}
+		}
+	}
+
+	/// Send an ethernet frame to `dst_addr`, its destination is set to the resolved address.
+	///
+	/// If `dst_addr` is not resolved yet the frame is queued and a request is sent, unless one is
+	/// already outstanding. `origin` is the ipv6 packet the frame was translated from, it is
+	/// answered with an ICMPv6 error if the resolution fails. Frames from a claimed `src_addr`
+	/// are dropped until it is bound.
+	pub async fn send(
 		&self,
-		if_dst_write: &mut RawPacketStream,
 		src_addr: Ipv4Addr,
-		dst_addr: Ipv4Addr,
-		if_mac: MacAddr,
-	) -> Result<Option<MacAddr>> {
-		{
-			if let Some(addr) = self.try_get(&dst_addr).await {
-				return Ok(Some(addr));
+		dst_addr: VlanAddr,
+		frame: Vec<u8>,
+		origin: &[u8],
+	) -> Result<()> {
+		let src = VlanAddr::new(dst_addr.vlan, src_addr);
+		if !self.usable(src) {
+			trace!("arp: {} is not bound, dropping frame to {}", src, dst_addr);
+			STATS.arp_unbound_drops.inc();
+			return Ok(());
+		}
+
+		let mut neighbors = self.neighbors.lock().await;
+		if !neighbors.contains_key(&dst_addr) && !self.make_room(&mut neighbors) {
+			drop(neighbors);
+			debug!("arp cache full, dropping frame to {}", dst_addr);
+			STATS.arp_queue_drops.inc();
+			return Ok(());
+		}
+		let entry = neighbors
+			.entry(dst_addr)
+			.or_insert_with(|| Neighbor::new(src_addr));
+		entry.src_addr = src_addr;
+		entry.expire(&self.config);
+
+		match entry.state {
+			NeighState::Reachable | NeighState::Probe | NeighState::Permanent => {
+				let mac = entry.mac;
+				drop(neighbors);
+				self.egress.send(with_destination(frame, mac)).await
+			}
+			NeighState::Stale => {
+				trace!("arp: {} is stale, probing {}", dst_addr, entry.mac);
+				entry.state = NeighState::Probe;
+				entry.probes = 1;
+				let (mac, timer) = (entry.mac, entry.start_timer());
+				drop(neighbors);
+
+				async_std::task::spawn(self.clone().timer(dst_addr, timer));
+				self.egress.send(with_destination(frame, mac)).await?;
+				self.request(src_addr, dst_addr, Some(mac)).await
+			}
+			NeighState::Incomplete => {
+				if entry.queue.len() >= self.config.unres_qlen.max(1) {
+					trace!("arp queue for {} full, dropping oldest frame", dst_addr);
+					entry.queue.pop_front();
+					STATS.arp_queue_drops.inc();
+				}
+				entry.queue.push_back(Queued {
+					frame,
+					origin: origin.to_vec(),
+				});
+				if entry.probes > 0 {
+					return Ok(());
+				}
+
+				entry.probes = 1;
+				let timer = entry.start_timer();
+				drop(neighbors);
+
+				async_std::task::spawn(self.clone().timer(dst_addr, timer));
+				self.request(src_addr, dst_addr, None).await
+			}
+			NeighState::Failed => {
+				drop(neighbors);
+				trace!("arp: {} failed, dropping frame", dst_addr);
+				STATS.arp_queue_drops.inc();
+				self.unreachable(origin).await;
+				Ok(())
 			}
 		}
+	}
+
+	/// Retransmit requests of the `Incomplete` and `Probe` states until the neighbor replies or
+	/// the probes are used up
+	async fn timer(self, dst_addr: VlanAddr, id: u64) {
+		loop {
+			let interval = match self.neighbors.lock().await.get(&dst_addr) {
+				Some(entry) if entry.timer == id => self.config.retrans_interval(entry.probes),
+				_ => return,
+			};
+			async_std::task::sleep(interval).await;
+
+			let mut neighbors = self.neighbors.lock().await;
+			let entry = match neighbors.get_mut(&dst_addr) {
+				Some(entry) if entry.timer == id => entry,
+				_ => return,
+			};
+			let (max_probes, target) = match entry.state {
+				NeighState::Incomplete => (self.config.mcast_probes, None),
+				NeighState::Probe => (self.config.ucast_probes, Some(entry.mac)),
+				_ => return,
+			};
+
+			if entry.probes >= max_probes {
+				debug!(
+					"no arp reply for {} after {} requests, dropping {} frames",
+					dst_addr,
+					entry.probes,
+					entry.queue.len()
+				);
+				let queue = entry.fail();
+				drop(neighbors);
 
-		Self::do_request(if_dst_write, src_addr, dst_addr, if_mac).await?;
+				STATS.arp_failed.inc();
+				STATS.arp_queue_drops.add(queue.len() as u64);
+				for queued in queue {
+					self.unreachable(&queued.origin).await;
+				}
+				return;
+			}
+
+			entry.probes += 1;
+			let src_addr = entry.src_addr;
+			drop(neighbors);
 
-		async_std::task::sleep(Duration::from_millis(100)).await;
-		for _ in 0..100 {
-			if let Some(addr) = self.try_get(&dst_addr).await {
-				return Ok(Some(addr));
+			if let Err(e) = self.request(src_addr, dst_addr, target).await {
+				warn!("failed to send arp request for {}: {}", dst_addr, e);
 			}
-			async_std::task::sleep(Duration::from_millis(50)).await;
 		}
+	}
 
-		Ok(None)
+	/// Tell the sender of `origin` that its destination is not reachable
+	async fn unreachable(&self, origin: &[u8]) {
+		if self.config.unreachable_rate == 0 || !self.unreachable_limit.lock().unwrap().check() {
+			return;
+		}
+
+		if let Some(packet) = icmp::address_unreachable(origin) {
+			STATS.icmp_unreachable.inc();
+			if let Err(e) = self.tun_egress.send(packet).await {
+				warn!("failed to send icmp unreachable: {}", e);
+			}
+		}
 	}
 
-	pub async fn try_get(&self, dst_addr: &Ipv4Addr) -> Option<MacAddr> {
-		let mut cache = self.cache.lock().await;
-		//trace!("Searching for address: {} ({:?})", dst_addr, cache);
-		cache.cache_get(&dst_addr).copied()
+	/// Merge the sender of a received arp packet.
+	///
+	/// Replies complete an outstanding resolution and are rejected otherwise, requests only
+	/// mark the sender stale, so it is confirmed before it's trusted. Returns false if the
+	/// packet was rejected.
+	async fn learn(&self, dst_pr_addr: VlanAddr, dst_hw_addr: MacAddr, reply: bool) -> bool {
+		let queue = {
+			let mut neighbors = self.neighbors.lock().await;
+			if reply {
+				match neighbors.get(&dst_pr_addr) {
+					Some(entry) if entry.is_outstanding() => (),
+					_ => return false,
+				}
+			} else if !neighbors.contains_key(&dst_pr_addr) && !self.make_room(&mut neighbors) {
+				trace!("arp cache full, not learning {}", dst_pr_addr);
+				return true;
+			}
+			let entry = neighbors
+				.entry(dst_pr_addr)
+				.or_insert_with(|| Neighbor::new(Ipv4Addr::UNSPECIFIED));
+			if entry.state == NeighState::Permanent {
+				trace!("arp: not overriding static neighbor {}", dst_pr_addr);
+				return true;
+			}
+			let known = entry.state != NeighState::Incomplete && entry.state != NeighState::Failed;
+			if known && entry.mac == dst_hw_addr && !reply {
+				// nothing new, keep the state and any running probe
+				return true;
+			}
+			if known && entry.mac != dst_hw_addr {
+				debug!("arp: {} moved to {}", dst_pr_addr, dst_hw_addr);
+			}
+
+			entry.state = if reply {
+				NeighState::Reachable
+			} else {
+				NeighState::Stale
+			};
+			entry.mac = dst_hw_addr;
+			entry.updated = Instant::now();
+			entry.probes = 0;
+			// stop retransmissions
+			entry.timer += 1;
+			std::mem::take(&mut entry.queue)
+		};
+
+		if queue.is_empty() {
+			return true;
+		}
+		trace!("flushing {} frames for {}", queue.len(), dst_pr_addr);
+		for queued in queue {
+			if let Err(e) = self
+				.egress
+				.send(with_destination(queued.frame, dst_hw_addr))
+				.await
+			{
+				warn!("failed to flush arp queue for {}: {}", dst_pr_addr, e);
+				break;
+			}
+		}
+		true
 	}
 
-	pub async fn set(&self, dst_pr_addr: Ipv4Addr, dst_hw_addr: MacAddr) {
-		trace!("trying to accquire cache");
-		let mut cache = self.cache.lock().await;
-		cache.cache_set(dst_pr_addr, dst_hw_addr);
-		trace!("cache after found: {:?}", cache);
+	/// Evict the least useful neighbor if the cache is full, returns false if none can go
+	fn make_room(&self, neighbors: &mut HashMap<VlanAddr, Neighbor>) -> bool {
+		if neighbors.len() < self.config.size.max(1) + self.permanent {
+			return true;
+		}
+
+		for entry in neighbors.values_mut() {
+			entry.expire(&self.config);
+		}
+		let victim = neighbors
+			.iter()
+			.filter_map(|(addr, entry)| Some((entry.eviction_rank()?, *addr)))
+			.min()
+			.map(|(_, addr)| addr);
+
+		match victim {
+			Some(addr) => {
+				trace!("arp cache full, evicting {}", addr);
+				neighbors.remove(&addr);
+				true
+			}
+			None => false,
+		}
 	}
 
-	async fn do_request(
-		if_dst_write: &mut RawPacketStream,
+	/// Broadcast a request for `dst_addr`, or unicast it to `target` to confirm a known address
+	async fn request(
+		&self,
 		src_addr: Ipv4Addr,
-		dst_addr: Ipv4Addr,
-		if_mac: MacAddr,
+		dst_addr: VlanAddr,
+		target: Option<MacAddr>,
 	) -> Result<()> {
-		let packet = Self::create_request(src_addr, dst_addr, if_mac);
+		// a request would update the caches of the neighbors to us
+		let src = VlanAddr::new(dst_addr.vlan, src_addr);
+		if !self.usable(src) {
+			trace!("arp: {} is not bound, not requesting {}", src, dst_addr);
+			return Ok(());
+		}
 
-		if_dst_write
+		let packet =
+			Self::create_request(src_addr, dst_addr.addr, dst_addr.vlan, self.mac(), target);
+
+		self.stream
+			.clone()
 			.write_all(&packet)
 			.await
 			.context("Write Arp packet")?;
@@ -83,7 +854,13 @@ impl ArpCache {
 		Ok(())
 	}
 
-	fn create_request(src_addr: Ipv4Addr, dst_addr: Ipv4Addr, if_mac: MacAddr) -> Vec<u8> {
+	fn create_request(
+		src_addr: Ipv4Addr,
+		dst_addr: Ipv4Addr,
+		vlan: Vlan,
+		if_mac: MacAddr,
+		target: Option<MacAddr>,
+	) -> Vec<u8> {
 		let arp = Arp {
 			hardware_type: ArpHardwareTypes::Ethernet,
 			protocol_type: EtherTypes::Ipv4,
@@ -92,7 +869,7 @@ impl ArpCache {
 			operation: ArpOperations::Request,
 			sender_hw_addr: if_mac,
 			sender_proto_addr: src_addr,
-			target_hw_addr: MacAddr::zero(),
+			target_hw_addr: target.unwrap_or_else(MacAddr::zero),
 			target_proto_addr: dst_addr,
 			payload: vec![],
 		};
@@ -103,7 +880,7 @@ impl ArpCache {
 		arp_packet.populate(&arp);
 
 		let ethernet = Ethernet {
-			destination: MacAddr::broadcast(),
+			destination: target.unwrap_or_else(MacAddr::broadcast),
 			source: if_mac,
 			ethertype: EtherTypes::Arp,
 			payload: arp_packet.packet().to_vec(),
@@ -114,7 +891,7 @@ impl ArpCache {
 		let mut ethernet_packet = MutableEthernetPacket::new(&mut ethernet_buf).unwrap();
 		ethernet_packet.populate(&ethernet);
 
-		ethernet_packet.packet().to_vec()
+		vlan.tag(ethernet_packet.packet().to_vec())
 	}
 
 	fn check_arp(arp: &ArpPacket<'_>) -> Result<()> {
@@ -126,50 +903,146 @@ impl ArpCache {
 			bail!("Invalid arp address length");
 		}
 
+		let sender_mac = arp.get_sender_hw_addr();
+		if sender_mac == MacAddr::zero() || sender_mac.0 & 0x01 != 0 {
+			bail!("Invalid arp sender {}", sender_mac);
+		}
+
+		let sender = arp.get_sender_proto_addr();
+		if sender.is_broadcast() || sender.is_multicast() {
+			bail!("Invalid arp sender {}", sender);
+		}
+
 		Ok(())
 	}
 
-	pub async fn parse_arp(
-		&self,
-		buf: &[u8],
-		if_mac: MacAddr,
-		dst_write: RawPacketStream,
-		send_arp_reply: bool,
-	) -> Result<()> {
+	/// Handle an arp packet received on `vlan`
+	pub async fn parse_arp(&self, buf: &[u8], vlan: Vlan, dst_write: Ipv4Stream) -> Result<()> {
 		let arp = ArpPacket::new(buf).context("Allocate arp packet")?;
 
 		if let Err(e) = Self::check_arp(&arp) {
 			trace!("{}", e);
+			STATS.arp_invalid.inc();
 			return Ok(());
 		}
 
-		if arp.get_operation() != ArpOperations::Reply {
-			if send_arp_reply {
-				return self.reply_arp(arp, if_mac, dst_write).await;
+		if let Some(addr) = self.detect_conflict(&arp, vlan) {
+			let packet = Self::create_request(addr.addr, addr.addr, addr.vlan, self.mac(), None);
+			if let Err(e) = dst_write.clone().write_all(&packet).await {
+				warn!("failed to defend {}: {}", addr, e);
+			}
+		}
+
+		let src_pr_addr = VlanAddr::new(vlan, arp.get_sender_proto_addr());
+		let src_hw_addr = arp.get_sender_hw_addr();
+		let dst_pr_addr = VlanAddr::new(vlan, arp.get_target_proto_addr());
+		// requests for translated addresses of a prefix are sent from those addresses
+		let for_us = MapResult::find_v4_by_local(dst_pr_addr.addr, vlan).is_some()
+			|| MapResult::find_v4_proxy(dst_pr_addr.addr, vlan).is_some();
+		// probes from the unspecified address and conflicts on our own addresses are not learned
+		let learnable = !src_pr_addr.addr.is_unspecified()
+			&& MapResult::find_v4_by_local(src_pr_addr.addr, vlan).is_none();
+
+		if arp.get_operation() == ArpOperations::Request {
+			if for_us && learnable {
+				trace!(
+					"Learned from arp request: '{} -> {}'",
+					src_pr_addr,
+					src_hw_addr
+				);
+				self.learn(src_pr_addr, src_hw_addr, false).await;
+			}
+			if self.serve {
+				return self.reply_arp(arp, vlan, dst_write).await;
 			}
 			trace!("Arp reply disabled");
 			return Ok(());
-		};
+		}
 
-		let src_pr_addr = arp.get_sender_proto_addr();
-		let src_hw_addr = arp.get_sender_hw_addr();
+		if arp.get_operation() != ArpOperations::Reply {
+			trace!("ignoring arp operation {:?}", arp.get_operation());
+			return Ok(());
+		}
 
-		trace!("Found arp: '{} -> {}'", src_pr_addr, src_hw_addr);
+		if !for_us || arp.get_target_hw_addr() != self.mac() {
+			trace!(
+				"arp reply for {} at {} is not for us",
+				dst_pr_addr,
+				arp.get_target_hw_addr()
+			);
+			STATS.arp_not_for_us.inc();
+			return Ok(());
+		}
 
-		self.set(src_pr_addr, src_hw_addr).await;
+		if !learnable || !self.learn(src_pr_addr, src_hw_addr, true).await {
+			debug!(
+				"unsolicited arp reply: '{} -> {}'",
+				src_pr_addr, src_hw_addr
+			);
+			STATS.arp_unsolicited.inc();
+			return Ok(());
+		}
+
+		trace!("Found arp: '{} -> {}'", src_pr_addr, src_hw_addr);
 
 		Ok(())
 	}
 
+	/// Look for another host using one of our addresses, returns the address to defend
+	fn detect_conflict(&self, arp: &ArpPacket<'_>, vlan: Vlan) -> Option<VlanAddr> {
+		let sender_mac = arp.get_sender_hw_addr();
+		if sender_mac == self.mac() {
+			return None;
+		}
+
+		let sender = VlanAddr::new(vlan, arp.get_sender_proto_addr());
+		let target = VlanAddr::new(vlan, arp.get_target_proto_addr());
+		let mut claims = self.claims.lock().unwrap();
+		if let Some(claim) = claims.get_mut(&sender) {
+			return claim.conflict(sender, sender_mac).then_some(sender);
+		}
+
+		// another host probing for an address we are probing for as well
+		if sender.addr.is_unspecified() && arp.get_operation() == ArpOperations::Request {
+			if let Some(claim) = claims.get_mut(&target) {
+				if claim.state == ClaimState::Probing {
+					claim.conflict(target, sender_mac);
+				}
+			}
+		}
+		None
+	}
+
 	async fn reply_arp(
 		&self,
 		arp: ArpPacket<'_>,
-		if_mac: MacAddr,
-		mut dst_write: RawPacketStream,
+		vlan: Vlan,
+		mut dst_write: Ipv4Stream,
 	) -> Result<()> {
+		let if_mac = self.mac();
 		let who = arp.get_target_proto_addr();
 		// check if a mapping exists where 'who' is the local_ipv4
-		if MapResult::find_v4_by_local(who).is_none() {
+		if MapResult::find_v4_by_local(who, vlan).is_some() {
+			if !self.serves(VlanAddr::new(vlan, who)) {
+				trace!("got arp request for {}, but it is not claimed", who);
+				return Ok(());
+			}
+		} else if MapResult::find_v4_proxy(who, vlan).is_some() {
+			// hosts announcing or probing for their own address are left alone
+			let sender = arp.get_sender_proto_addr();
+			if sender == who || sender.is_unspecified() {
+				return Ok(());
+			}
+			if self.config.proxy_rate == 0 {
+				trace!("proxy arp is disabled, not answering for {}", who);
+				return Ok(());
+			}
+			if !self.proxy_limit.lock().unwrap().check() {
+				trace!("proxy arp rate limit exceeded, not answering for {}", who);
+				STATS.arp_proxy_limited.inc();
+				return Ok(());
+			}
+		} else {
 			trace!("got arp request, but don't serve {}", who);
 			return Ok(());
 		}
@@ -204,10 +1077,41 @@ impl ArpCache {
 		let mut ethernet_packet = MutableEthernetPacket::new(&mut ethernet_buffer).unwrap();
 		ethernet_packet.populate(&ethernet);
 
-		let packet = ethernet_packet.packet().to_vec();
+		let packet = vlan.tag(ethernet_packet.packet().to_vec());
 
 		dst_write.write_all(&packet).await?;
 
 		Ok(())
 	}
 }
+
+fn mac_from_str<'de, D: Deserializer<'de>>(deserializer: D) -> StdResult<MacAddr, D::Error> {
+	let mac = String::deserialize(deserializer)?;
+	mac.parse()
+		.map_err(|_| D::Error::custom(format!("Invalid mac address {}", mac)))
+}
+
+fn mac_to_str<S: Serializer>(mac: &MacAddr, serializer: S) -> StdResult<S::Ok, S::Error> {
+	serializer.collect_str(mac)
+}
+
+fn unix_time() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_secs()
+}
+
+/// Random duration up to `max`, so hosts starting at the same time do not probe in sync
+fn jitter(max: Duration) -> Duration {
+	let random = RandomState::new().build_hasher().finish();
+	max.mul_f64((random >> 11) as f64 / (1u64 << 53) as f64)
+}
+
+/// Set the destination of a queued frame once its neighbor is resolved
+fn with_destination(mut frame: Vec<u8>, dst: MacAddr) -> Vec<u8> {
+	if let Some(mut ethernet) = MutableEthernetPacket::new(&mut frame) {
+		ethernet.set_destination(dst);
+	}
+	frame
+}
@@ -0,0 +1,162 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use async_std::channel::{bounded, Receiver, Sender, TryRecvError};
+use log::*;
+use serde::Deserialize;
+use tun::{AsyncTunSocket, VirtioNetHdr};
+
+use crate::config::stats::STATS;
+use crate::config::stream::Ipv4Stream;
+use crate::config::tap::TapLink;
+
+/// Egress batching. The tun is only batched if it takes udp super packets, the datagrams of a
+/// batch are merged into as few of them as possible.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct BatchConfig {
+	/// Maximum number of packets emitted at once, 1 disables batching
+	pub size: usize,
+	/// Time in µs to wait for more packets before a partial batch is emitted
+	pub deadline: u64,
+	/// Number of packets that can be queued for a writer
+	pub queue: usize,
+}
+
+impl Default for BatchConfig {
+	fn default() -> Self {
+		Self {
+			size: 32,
+			deadline: 200,
+			queue: 1024,
+		}
+	}
+}
+
+/// Destination of a batch writer
+enum Sink {
+	Ipv4(Ipv4Stream),
+	/// Consecutive udp datagrams of a flow are merged, if `uso` is set
+	Tun {
+		tun: AsyncTunSocket,
+		uso: bool,
+		buf: Vec<u8>,
+	},
+	Tap(AsyncTunSocket, TapLink),
+}
+
+impl Sink {
+	async fn write_batch(&mut self, frames: &[Vec<u8>]) -> Result<()> {
+		match self {
+			Sink::Ipv4(stream) => {
+				STATS.ipv4_batches.record(frames.len() as u64);
+				stream.write_batch(frames).await?
+			}
+			Sink::Tun { tun, uso, buf } => {
+				let mut rest = frames;
+				while !rest.is_empty() {
+					let super_packet = if *uso {
+						VirtioNetHdr::coalesce_udp(rest, buf)
+					} else {
+						None
+					};
+					let count = match super_packet {
+						Some((hdr, count)) => {
+							tun.send_vnet(&hdr, buf).await?;
+							count
+						}
+						None => {
+							tun.send_vnet(&VirtioNetHdr::default(), &rest[0]).await?;
+							1
+						}
+					};
+					STATS.tun_batches.record(count as u64);
+					rest = &rest[count..];
+				}
+			}
+			Sink::Tap(tun, link) => {
+				for frame in frames.iter().filter_map(|packet| link.egress(packet)) {
+					tun.send_vnet(&VirtioNetHdr::default(), &frame).await?;
+				}
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// Handle to queue packets for a writer task, which emits them in batches
+#[derive(Debug, Clone)]
+pub struct BatchWriter(Sender<Vec<u8>>);
+
+impl BatchWriter {
+	pub fn ipv4(stream: Ipv4Stream, config: &BatchConfig) -> Self {
+		let deadline = Duration::from_micros(config.deadline);
+		Self::spawn(Sink::Ipv4(stream), config.queue, config.size, deadline)
+	}
+
+	/// Writer for the tun. With `uso` the packets are batched and merged into udp super packets,
+	/// otherwise every packet is written as soon as it is queued.
+	pub fn tun(tun: AsyncTunSocket, uso: bool, config: &BatchConfig) -> Self {
+		let sink = Sink::Tun {
+			tun,
+			uso,
+			buf: Vec::new(),
+		};
+		if uso {
+			let deadline = Duration::from_micros(config.deadline);
+			Self::spawn(sink, config.queue, config.size, deadline)
+		} else {
+			Self::spawn(sink, config.queue, 1, Duration::ZERO)
+		}
+	}
+
+	/// Writer for an ipv6 tap, packets are framed for their neighbor and written as soon as they
+	/// are queued
+	pub fn tap(tun: AsyncTunSocket, link: TapLink, config: &BatchConfig) -> Self {
+		Self::spawn(Sink::Tap(tun, link), config.queue, 1, Duration::ZERO)
+	}
+
+	fn spawn(sink: Sink, queue: usize, size: usize, deadline: Duration) -> Self {
+		let (sender, receiver) = bounded(queue.max(1));
+
+		async_std::task::spawn(Self::run(sink, receiver, size.max(1), deadline));
+
+		Self(sender)
+	}
+
+	/// Queue a packet, waits if the writer is behind
+	pub async fn send(&self, packet: Vec<u8>) -> Result<()> {
+		self.0.send(packet).await.context("Batch writer stopped")
+	}
+
+	async fn run(mut sink: Sink, receiver: Receiver<Vec<u8>>, size: usize, deadline: Duration) {
+		let mut batch = Vec::with_capacity(size);
+
+		while let Ok(packet) = receiver.recv().await {
+			batch.push(packet);
+
+			let flush_at = Instant::now() + deadline;
+			while batch.len() < size {
+				match receiver.try_recv() {
+					Ok(packet) => batch.push(packet),
+					Err(TryRecvError::Empty) => {
+						let timeout = flush_at.saturating_duration_since(Instant::now());
+						match async_std::future::timeout(timeout, receiver.recv()).await {
+							Ok(Ok(packet)) => batch.push(packet),
+							_ => break,
+						}
+					}
+					Err(TryRecvError::Closed) => break,
+				}
+			}
+
+			if let Err(e) = sink.write_batch(&batch).await {
+				warn!("failed to write batch of {} packets: {}", batch.len(), e);
+			}
+			batch.clear();
+		}
+
+		debug!("batch writer stopped");
+	}
+}
@@ -0,0 +1,40 @@
+use anyhow::{Context, Result};
+use async_std::channel::{unbounded, Receiver};
+use log::*;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::iterator::Signals;
+
+/// Requests to the running translator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+	/// Reread the mappings from the config file
+	Reload,
+	/// Log the contents of the arp cache
+	DumpArp,
+	/// Clean up and exit
+	Shutdown,
+}
+
+/// Translate SIGHUP into reloads, SIGUSR1 into arp cache dumps and SIGINT/SIGTERM into a
+/// shutdown
+pub fn events() -> Result<Receiver<Event>> {
+	let mut signals =
+		Signals::new([SIGHUP, SIGUSR1, SIGINT, SIGTERM]).context("Register signal handlers")?;
+	let (sender, receiver) = unbounded();
+
+	std::thread::spawn(move || {
+		for signal in signals.forever() {
+			trace!("got signal {}", signal);
+			let event = match signal {
+				SIGHUP => Event::Reload,
+				SIGUSR1 => Event::DumpArp,
+				_ => Event::Shutdown,
+			};
+			if sender.try_send(event).is_err() {
+				break;
+			}
+		}
+	});
+
+	Ok(receiver)
+}
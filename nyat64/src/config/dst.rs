@@ -1,32 +1,32 @@
-use afpacket::r#async::RawPacketStream;
 use anyhow::{Context, Result};
 use async_std::io::prelude::*;
 use async_std::net::Ipv6Addr;
 use log::*;
 #[cfg(feature = "nom")]
 use nom::HexDisplay;
-use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+use pnet::packet::ethernet::EtherTypes;
 use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::ipv4::Ipv4Packet;
 use pnet::packet::ipv6::MutableIpv6Packet;
 use pnet::packet::{FromPacket, MutablePacket, Packet, PacketSize};
-use pnet::util::MacAddr;
-use tun::AsyncTunSocket;
 
 use crate::config::arp::ArpCache;
-use crate::config::MapResult;
+use crate::config::batch::BatchWriter;
+use crate::config::stream::Ipv4Stream;
+use crate::config::{vlan, MapResult};
+
+/// Mtu of 1500 with the ethernet header and two vlan tags
+const MAX_FRAME: usize = 1500 + 14 + 8;
 
 pub async fn dst_to_tun(
-	mut iface_dst_read: RawPacketStream,
-	tun: AsyncTunSocket,
+	mut iface_dst_read: Ipv4Stream,
+	tun: BatchWriter,
 	arp_cache: ArpCache,
-	dst_mac: MacAddr,
-	send_arp_reply: bool,
 ) -> Result<()> {
 	debug!("starting loop dst");
 
 	loop {
-		let mut buf = [0u8; 1500];
+		let mut buf = [0u8; MAX_FRAME];
 		let size = iface_dst_read
 			.read(&mut buf)
 			.await
@@ -38,17 +38,7 @@ pub async fn dst_to_tun(
 		let arp_cache = arp_cache.clone();
 		let dst_write = iface_dst_read.clone();
 		async_std::task::spawn(async move {
-			if let Err(e) = parse(
-				buf,
-				size,
-				tun,
-				arp_cache,
-				dst_mac,
-				dst_write,
-				send_arp_reply,
-			)
-			.await
-			{
+			if let Err(e) = parse(buf, size, tun, arp_cache, dst_write).await {
 				info!("failed to parse dst packet: {}", e);
 			}
 		});
@@ -56,32 +46,33 @@ pub async fn dst_to_tun(
 }
 
 async fn parse(
-	mut buf: [u8; 1500],
+	buf: [u8; MAX_FRAME],
 	size: usize,
-	tun: AsyncTunSocket,
+	tun: BatchWriter,
 	arp_cache: ArpCache,
-	dst_mac: MacAddr,
-	dst_write: RawPacketStream,
-	send_arp_reply: bool,
+	dst_write: Ipv4Stream,
 ) -> Result<()> {
 	#[cfg(feature = "debug")]
 	debug!("dst:\n{}", &(buf[..size]).to_hex(24));
 
-	let ethernet = MutableEthernetPacket::new(&mut buf).context("Failed to allocate ethernet")?;
+	let (vlan, ethertype, length) = match vlan::parse(&buf[..size]) {
+		Some(header) => header,
+		None => {
+			debug!("Invalid ethernet header");
+			return Ok(());
+		}
+	};
 
-	if ethernet.get_ethertype() == EtherTypes::Arp {
-		return arp_cache
-			.parse_arp(ethernet.payload(), dst_mac, dst_write, send_arp_reply)
-			.await;
+	if ethertype == EtherTypes::Arp {
+		return arp_cache.parse_arp(&buf[length..], vlan, dst_write).await;
 	}
 
-	if ethernet.get_ethertype() != EtherTypes::Ipv4 {
-		debug!("Invalid next header Protocol: {}", ethernet.get_ethertype());
+	if ethertype != EtherTypes::Ipv4 {
+		debug!("Invalid next header Protocol: {}", ethertype);
 		return Ok(());
 	}
-	let length = ethernet.packet_size();
 
-	let ipv4 = Ipv4Packet::new(ethernet.payload()).context("Failed to allocate ipv4 packet")?;
+	let ipv4 = Ipv4Packet::new(&buf[length..]).context("Failed to allocate ipv4 packet")?;
 	trace!("ipv4: {:?}", ipv4);
 
 	if let Err(e) = super::supports(ipv4.get_next_level_protocol()) {
@@ -94,7 +85,7 @@ async fn parse(
 	//let payload_start = length + ipv4.packet_size();
 	let payload_start = length + ipv4.get_header_length() as usize * 4;
 
-	let map_result = MapResult::find_v4(src_addr4, dst_addr4).context("No Mappings found");
+	let map_result = MapResult::find_v4(src_addr4, dst_addr4, vlan).context("No Mappings found");
 	if let Err(e) = map_result {
 		debug!("{}", e);
 		return Ok(());
@@ -117,11 +108,11 @@ async fn parse(
 }
 
 async fn parse_udp(
-	mut buf: [u8; 1500],
+	mut buf: [u8; MAX_FRAME],
 	udp_start: usize,
 	src: Ipv6Addr,
 	dst: Ipv6Addr,
-	mut tun: AsyncTunSocket,
+	tun: BatchWriter,
 ) -> Result<()> {
 	use pnet::packet::udp::{MutableUdpPacket, UdpPacket};
 
@@ -156,7 +147,7 @@ async fn parse_udp(
 
 	trace!("writing v6: {:?}", ipv6);
 
-	tun.write_all(&ipv6.packet()[..length]).await?;
+	tun.send(ipv6.packet()[..length].to_vec()).await?;
 
 	Ok(())
 }
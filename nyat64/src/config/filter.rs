@@ -0,0 +1,314 @@
+use std::io::{Error as IoError, ErrorKind};
+use std::mem::size_of;
+use std::net::Ipv4Addr;
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{bail, Context, Result};
+use nix::libc::{self, sock_filter, sock_fprog};
+
+use crate::config::vlan::Vlan;
+
+/// Ipv4 destinations admitted or refused by the filters, the longest matching prefix decides
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Rule {
+	pub vlan: Vlan,
+	pub addr: Ipv4Addr,
+	pub prefix_len: u8,
+	pub accept: bool,
+}
+
+impl Rule {
+	pub fn new(vlan: Vlan, addr: Ipv4Addr, prefix_len: u8, accept: bool) -> Self {
+		let prefix_len = prefix_len.min(32);
+		Self {
+			vlan,
+			addr: Ipv4Addr::from(u32::from(addr) & mask(prefix_len)),
+			prefix_len,
+			accept,
+		}
+	}
+
+	pub fn contains(&self, addr: Ipv4Addr) -> bool {
+		u32::from(addr) & mask(self.prefix_len) == u32::from(self.addr)
+	}
+}
+
+/// Build a classic BPF program admitting ARP and the ipv4 frames accepted by `rules`.
+///
+/// Every rule is matched with its own return instruction, longer prefixes first, so all jumps
+/// stay short and the length of the list is only limited by the maximum program size of the
+/// kernel. Up to two vlan tags are skipped, the index register holds their length. The tags are
+/// not compared, an address refused on one vlan but accepted on another is admitted.
+pub fn build(rules: &[Rule]) -> Result<Vec<sock_filter>> {
+	let accepted_elsewhere = |rule: &Rule| {
+		rules
+			.iter()
+			.any(|other| other.accept && other.vlan != rule.vlan && other.contains(rule.addr))
+	};
+	let mut dsts: Vec<(u8, u32, bool)> = rules
+		.iter()
+		.filter(|rule| rule.accept || !accepted_elsewhere(rule))
+		.map(|rule| (rule.prefix_len, u32::from(rule.addr), rule.accept))
+		.collect();
+	dsts.sort_unstable_by(|a, b| b.cmp(a));
+	dsts.dedup();
+
+	let mut prog = Vec::with_capacity(15 + dsts.len() * 4);
+
+	// vlan tags
+	prog.push(stmt(BPF_LDX | BPF_W | BPF_IMM, 0));
+	prog.push(stmt(BPF_LD | BPF_H | BPF_ABS, 12));
+	prog.push(jump(BPF_JMP | BPF_JEQ | BPF_K, ETH_P_8021Q, 1, 0));
+	prog.push(jump(BPF_JMP | BPF_JEQ | BPF_K, ETH_P_8021AD, 0, 5));
+	prog.push(stmt(BPF_LDX | BPF_W | BPF_IMM, 4));
+	prog.push(stmt(BPF_LD | BPF_H | BPF_ABS, 16));
+	prog.push(jump(BPF_JMP | BPF_JEQ | BPF_K, ETH_P_8021Q, 0, 2));
+	prog.push(stmt(BPF_LDX | BPF_W | BPF_IMM, 8));
+	prog.push(stmt(BPF_LD | BPF_H | BPF_ABS, 20));
+
+	// ethertype
+	prog.push(jump(BPF_JMP | BPF_JEQ | BPF_K, ETH_P_ARP, 0, 1));
+	prog.push(stmt(BPF_RET | BPF_K, ACCEPT));
+	prog.push(jump(BPF_JMP | BPF_JEQ | BPF_K, ETH_P_IP, 1, 0));
+	prog.push(stmt(BPF_RET | BPF_K, DROP));
+
+	// ipv4 destination address
+	prog.push(stmt(BPF_LD | BPF_W | BPF_IND, 14 + 16));
+	for (prefix_len, addr, accept) in dsts {
+		let verdict = stmt(BPF_RET | BPF_K, if accept { ACCEPT } else { DROP });
+		if prefix_len == 32 {
+			prog.push(jump(BPF_JMP | BPF_JEQ | BPF_K, addr, 0, 1));
+			prog.push(verdict);
+		} else {
+			// the mask clobbers the address, it is loaded again for the next rule
+			prog.push(stmt(BPF_ALU | BPF_AND | BPF_K, mask(prefix_len)));
+			prog.push(jump(BPF_JMP | BPF_JEQ | BPF_K, addr, 0, 1));
+			prog.push(verdict);
+			prog.push(stmt(BPF_LD | BPF_W | BPF_IND, 14 + 16));
+		}
+	}
+	prog.push(stmt(BPF_RET | BPF_K, DROP));
+
+	if prog.len() > BPF_MAXINSNS {
+		bail!("Too many addresses for a socket filter: {}", rules.len());
+	}
+
+	Ok(prog)
+}
+
+/// Attach `prog` to the socket, replacing the previous filter
+pub fn attach(socket: &impl AsRawFd, prog: &[sock_filter]) -> Result<()> {
+	let fprog = sock_fprog {
+		len: prog.len() as _,
+		filter: prog.as_ptr() as *mut _,
+	};
+
+	// SAFETY: call to c function, the kernel copies the program before returning
+	let res = unsafe {
+		libc::setsockopt(
+			socket.as_raw_fd(),
+			libc::SOL_SOCKET,
+			libc::SO_ATTACH_FILTER,
+			&fprog as *const _ as _,
+			size_of::<sock_fprog>() as _,
+		)
+	};
+	if res < 0 {
+		return Err(IoError::last_os_error()).context("setsockopt SO_ATTACH_FILTER");
+	}
+
+	Ok(())
+}
+
+/// Drop all frames until the real filter is attached and discard the ones received before, so
+/// no unfiltered frame reaches the receive loops. Called before the socket is bound.
+pub fn block(socket: &impl AsRawFd) -> Result<()> {
+	attach(socket, &[stmt(BPF_RET | BPF_K, DROP)])?;
+
+	let mut buf = [0u8; 1];
+	loop {
+		// SAFETY: call to c function, buf is valid for its length
+		let res = unsafe {
+			libc::recv(
+				socket.as_raw_fd(),
+				buf.as_mut_ptr() as _,
+				buf.len(),
+				libc::MSG_DONTWAIT,
+			)
+		};
+		if res < 0 {
+			match IoError::last_os_error() {
+				e if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+				e => return Err(e).context("Drain socket"),
+			}
+		}
+	}
+}
+
+fn mask(prefix_len: u8) -> u32 {
+	u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+}
+
+fn stmt(code: u16, k: u32) -> sock_filter {
+	jump(code, k, 0, 0)
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> sock_filter {
+	sock_filter { code, jt, jf, k }
+}
+
+const ACCEPT: u32 = 0x0004_0000;
+const DROP: u32 = 0;
+
+const ETH_P_IP: u32 = 0x0800;
+const ETH_P_ARP: u32 = 0x0806;
+const ETH_P_8021Q: u32 = 0x8100;
+const ETH_P_8021AD: u32 = 0x88a8;
+
+// libc helpers not defined in libc
+const BPF_MAXINSNS: usize = 4096;
+
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_W: u16 = 0x00;
+const BPF_H: u16 = 0x08;
+const BPF_IMM: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_AND: u16 = 0x50;
+const BPF_JEQ: u16 = 0x10;
+const BPF_K: u16 = 0x00;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Run the instructions `build` emits on `frame`, like the kernel does
+	fn run(prog: &[sock_filter], frame: &[u8]) -> u32 {
+		let load = |offset: u32, len: usize| -> Option<u32> {
+			let bytes = frame.get(offset as usize..offset as usize + len)?;
+			Some(bytes.iter().fold(0, |acc, byte| acc << 8 | *byte as u32))
+		};
+		let (mut a, mut x, mut pc) = (0u32, 0u32, 0usize);
+		loop {
+			let insn = prog[pc];
+			pc += 1;
+			match insn.code {
+				code if code == BPF_LDX | BPF_W | BPF_IMM => x = insn.k,
+				code if code == BPF_LD | BPF_H | BPF_ABS => match load(insn.k, 2) {
+					Some(value) => a = value,
+					None => return DROP,
+				},
+				code if code == BPF_LD | BPF_W | BPF_IND => match load(x + insn.k, 4) {
+					Some(value) => a = value,
+					None => return DROP,
+				},
+				code if code == BPF_ALU | BPF_AND | BPF_K => a &= insn.k,
+				code if code == BPF_JMP | BPF_JEQ | BPF_K => {
+					pc += if a == insn.k { insn.jt } else { insn.jf } as usize;
+				}
+				code if code == BPF_RET | BPF_K => return insn.k,
+				code => panic!("unexpected instruction {:#x}", code),
+			}
+		}
+	}
+
+	fn frame(tags: &[u32], ethertype: u16, dst: [u8; 4]) -> Vec<u8> {
+		let mut frame = vec![0xff; 12];
+		for tpid in tags {
+			frame.extend_from_slice(&(*tpid as u16).to_be_bytes());
+			frame.extend_from_slice(&[0, 10]);
+		}
+		frame.extend_from_slice(&ethertype.to_be_bytes());
+		let mut ipv4 = [0u8; 20];
+		ipv4[0] = 0x45;
+		ipv4[16..].copy_from_slice(&dst);
+		frame.extend_from_slice(&ipv4);
+		frame
+	}
+
+	fn rule(vlan: Option<u16>, addr: [u8; 4], prefix_len: u8, accept: bool) -> Rule {
+		let vlan = Vlan {
+			inner: vlan,
+			outer: None,
+		};
+		Rule::new(vlan, addr.into(), prefix_len, accept)
+	}
+
+	#[test]
+	fn jump_targets() {
+		let rules = [
+			rule(None, [192, 0, 2, 1], 32, true),
+			rule(None, [192, 0, 2, 0], 24, true),
+			rule(None, [192, 0, 2, 7], 32, false),
+			rule(None, [10, 0, 0, 0], 8, true),
+			rule(None, [10, 1, 0, 0], 16, false),
+		];
+		let prog = build(&rules).unwrap();
+		for (pc, insn) in prog.iter().enumerate() {
+			if insn.code == BPF_JMP | BPF_JEQ | BPF_K {
+				assert!(pc + 1 + (insn.jt.max(insn.jf) as usize) < prog.len());
+			}
+		}
+		assert_eq!(prog.last().unwrap().code, BPF_RET | BPF_K);
+
+		for tags in [&[][..], &[ETH_P_8021Q], &[ETH_P_8021AD, ETH_P_8021Q]].iter() {
+			let verdict = |dst| run(&prog, &frame(tags, 0x0800, dst));
+			assert_eq!(verdict([192, 0, 2, 1]), ACCEPT);
+			assert_eq!(verdict([192, 0, 2, 50]), ACCEPT);
+			assert_eq!(verdict([192, 0, 2, 7]), DROP);
+			assert_eq!(verdict([192, 0, 3, 1]), DROP);
+			assert_eq!(verdict([10, 2, 0, 1]), ACCEPT);
+			assert_eq!(verdict([10, 1, 0, 1]), DROP);
+			assert_eq!(run(&prog, &frame(tags, 0x0806, [0; 4])), ACCEPT);
+			assert_eq!(run(&prog, &frame(tags, 0x86dd, [192, 0, 2, 1])), DROP);
+		}
+	}
+
+	#[test]
+	fn empty_rules() {
+		let prog = build(&[]).unwrap();
+		assert_eq!(run(&prog, &frame(&[], 0x0800, [192, 0, 2, 1])), DROP);
+		assert_eq!(run(&prog, &frame(&[], 0x0806, [0; 4])), ACCEPT);
+		assert_eq!(run(&prog, &[0xff; 10]), DROP);
+	}
+
+	#[test]
+	fn refused_on_other_vlan() {
+		// the tags are not compared, what one vlan accepts is admitted on all
+		let rules = [
+			rule(None, [192, 0, 2, 0], 24, true),
+			rule(Some(10), [192, 0, 2, 0], 24, true),
+			rule(Some(10), [192, 0, 2, 7], 32, false),
+			rule(Some(10), [192, 0, 3, 7], 32, false),
+		];
+		let prog = build(&rules).unwrap();
+		assert_eq!(
+			run(&prog, &frame(&[ETH_P_8021Q], 0x0800, [192, 0, 2, 7])),
+			ACCEPT
+		);
+		assert_eq!(
+			run(&prog, &frame(&[ETH_P_8021Q], 0x0800, [192, 0, 3, 7])),
+			DROP
+		);
+	}
+
+	#[test]
+	fn too_many_rules() {
+		let rules: Vec<Rule> = (0..BPF_MAXINSNS as u32 / 2)
+			.map(|i| rule(None, i.to_be_bytes(), 32, true))
+			.collect();
+		assert!(build(&rules).is_err());
+	}
+
+	#[test]
+	fn rule_masks_address() {
+		let rule = rule(None, [192, 0, 2, 77], 26, true);
+		assert_eq!(rule.addr, Ipv4Addr::new(192, 0, 2, 64));
+		assert!(rule.contains(Ipv4Addr::new(192, 0, 2, 127)));
+		assert!(!rule.contains(Ipv4Addr::new(192, 0, 2, 128)));
+	}
+}
@@ -0,0 +1,57 @@
+use std::net::Ipv6Addr;
+
+use pnet::packet::icmpv6::{self, Icmpv6Packet};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+
+/// Wrap an ICMPv6 message into an ipv6 packet and fill in the checksum
+pub fn packet(src: Ipv6Addr, dst: Ipv6Addr, hop_limit: u8, icmp: &mut [u8]) -> Vec<u8> {
+	let csum = icmpv6::checksum(&Icmpv6Packet::new(icmp).unwrap(), &src, &dst);
+	icmp[2..4].copy_from_slice(&csum.to_be_bytes());
+
+	let mut buf = vec![0u8; IPV6_LEN + icmp.len()];
+	let mut ipv6 = MutableIpv6Packet::new(&mut buf).unwrap();
+	ipv6.set_version(6);
+	ipv6.set_payload_length(icmp.len() as u16);
+	ipv6.set_next_header(IpNextHeaderProtocols::Icmpv6);
+	ipv6.set_hop_limit(hop_limit);
+	ipv6.set_source(src);
+	ipv6.set_destination(dst);
+	ipv6.set_payload(icmp);
+	buf
+}
+
+/// Destination Unreachable (Address Unreachable) for `invoking`, sent from its destination.
+///
+/// Returns `None` for packets that must not trigger an error (RFC 4443 2.4).
+pub fn address_unreachable(invoking: &[u8]) -> Option<Vec<u8>> {
+	let ipv6 = Ipv6Packet::new(invoking)?;
+	let src = ipv6.get_source();
+	let dst = ipv6.get_destination();
+	if src.is_unspecified() || src.is_multicast() || dst.is_multicast() {
+		return None;
+	}
+	if ipv6.get_next_header() == IpNextHeaderProtocols::Icmpv6 {
+		// never answer an error with an error
+		match invoking.get(IPV6_LEN) {
+			Some(ty) if *ty >= 128 => (),
+			_ => return None,
+		}
+	}
+
+	// as much of the invoking packet as fits into the minimum MTU
+	let len = invoking.len().min(MIN_MTU - IPV6_LEN - ICMP_LEN);
+	let mut icmp = vec![0u8; ICMP_LEN + len];
+	icmp[0] = ICMP6_DST_UNREACH;
+	icmp[1] = ICMP6_DST_UNREACH_ADDR;
+	icmp[ICMP_LEN..].copy_from_slice(&invoking[..len]);
+
+	Some(packet(dst, src, 64, &mut icmp))
+}
+
+const IPV6_LEN: usize = 40;
+const ICMP_LEN: usize = 8;
+const MIN_MTU: usize = 1280;
+
+const ICMP6_DST_UNREACH: u8 = 1;
+const ICMP6_DST_UNREACH_ADDR: u8 = 3;
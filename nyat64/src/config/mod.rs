@@ -1,31 +1,65 @@
-use std::convert::Infallible;
+use std::convert::{Infallible, TryFrom};
 use std::fmt::Formatter;
+use std::future::Future;
 use std::marker::PhantomData;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use std::os::unix::io::FromRawFd;
+use std::pin::Pin;
 use std::result::Result as StdResult;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
 
 use afpacket::r#async::RawPacketStream;
 use anyhow::{bail, Context, Result};
 use async_std::prelude::FutureExt;
 use cached::proc_macro::cached;
-use iptool::{IpTool, MacAddrLinxExt};
+use iptool::MacAddrLinxExt;
 use log::*;
-use nix::libc;
 use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::util::MacAddr;
 use serde::de::{Error, MapAccess, Visitor};
 use serde::{Deserialize, Deserializer};
-use tun::AsyncTunSocket;
+use tun::netlink::{Address, LinkConfig, Netlink};
+use tun::vnet::offload;
+use tun::{AsyncTunSocket, Mode, TunOptions};
 
 mod arp;
+mod batch;
+mod control;
 mod dst;
+mod filter;
+mod icmp;
+mod packet;
+mod ratelimit;
+mod ring;
+mod route;
 mod src;
+mod stats;
+mod stream;
+mod tap;
+mod vlan;
+mod xdp;
 
-use crate::config::arp::ArpCache;
+use crate::config::arp::{ArpCache, ArpConfig};
+use crate::config::batch::{BatchConfig, BatchWriter};
+use crate::config::control::Event;
+use crate::config::ring::{RingConfig, RingStream};
+use crate::config::route::{RouteManager, RoutesConfig};
+use crate::config::stream::Ipv4Stream;
+use crate::config::tap::TapLink;
+use crate::config::vlan::{Vlan, VlanAddr};
+use crate::config::xdp::{XdpConfig, XskStream};
 
-static mut MAPPINGS: Vec<MapConfig> = Vec::new();
+static MAPPINGS: RwLock<Vec<MapConfig>> = RwLock::new(Vec::new());
+/// Changed with every update of `MAPPINGS`, part of the key of the cached lookups, so results
+/// of older mappings are never returned
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+type LoopFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// How often the hardware address of the ipv4 interface is checked for changes
+const MAC_POLL_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Deserialize, Default)]
 pub struct InterfaceConfig {
@@ -35,8 +69,94 @@ pub struct InterfaceConfig {
 
 	pub mask: Option<u32>,
 
+	/// Further addresses, set together with `address`
+	#[serde(default)]
+	pub addresses: Vec<AddressConfig>,
+
 	#[serde(default)]
 	pub mtu: u32,
+
+	pub txqueuelen: Option<u32>,
+
+	/// Number of sockets (ipv4) used to spread receive processing, 0 and 1 open a single one
+	#[serde(default)]
+	pub queues: u16,
+
+	/// PACKET_FANOUT group id, defaults to the lower bits of the pid
+	pub fanout_group: Option<u16>,
+
+	/// Socket type used for the ipv4 interface
+	#[serde(default)]
+	pub backend: Ipv4Backend,
+
+	/// Ring sizes for the `ring` backend
+	#[serde(default)]
+	pub ring: RingConfig,
+
+	/// Socket settings for the `xdp` backend
+	#[serde(default)]
+	pub xdp: XdpConfig,
+
+	/// Let the kernel hand over GSO super packets with partial checksums (ipv6 only)
+	#[serde(default)]
+	pub offload: bool,
+
+	/// Use a tap device with ethernet framing instead of a tun (ipv6 only)
+	#[serde(default)]
+	pub tap: bool,
+
+	/// Let the kernel tag every packet with its protocol (ipv6 only)
+	#[serde(default)]
+	pub packet_info: bool,
+
+	/// Keep the tun/tap device after nyat64 exits (ipv6 only)
+	#[serde(default)]
+	pub persist: bool,
+
+	/// User allowed to attach to the device without privileges (ipv6 only)
+	pub owner: Option<u32>,
+
+	/// Group allowed to attach to the device without privileges (ipv6 only)
+	pub group: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Ipv4Backend {
+	/// Plain AF_PACKET socket
+	#[default]
+	Packet,
+	/// AF_PACKET socket with mmaped TPACKET_V3 rings
+	Ring,
+	/// AF_XDP socket, frames are redirected by an XDP program
+	Xdp,
+	/// Tap device created by nyat64, the kernel side is the ipv4 network
+	Tap,
+}
+
+impl InterfaceConfig {
+	/// All configured addresses, without a mask ipv6 defaults to /64 and ipv4 to /32
+	fn addresses(&self) -> Vec<Address> {
+		let first = self.address.map(|address| AddressConfig {
+			address,
+			mask: self.mask,
+		});
+
+		first
+			.iter()
+			.chain(self.addresses.iter())
+			.map(|a| Address {
+				addr: a.address,
+				prefix_len: a.mask.unwrap_or(if a.address.is_ipv6() { 64 } else { 32 }) as u8,
+			})
+			.collect()
+	}
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct AddressConfig {
+	pub address: IpAddr,
+	pub mask: Option<u32>,
 }
 
 impl FromStr for InterfaceConfig {
@@ -102,6 +222,112 @@ pub struct MapConfig {
 	pub ipv6_local: Ipv6Addr,
 	pub ipv6_remote: Ipv6Addr,
 	pub ipv4_gateway: Option<Ipv4Addr>,
+
+	/// Prefix length of the route installed for `ipv6_local`, holding the addresses `ipv4_prefix`
+	/// is mapped to, defaults to 128
+	pub ipv6_prefix: Option<u8>,
+
+	/// Prefix length around `ipv4_local` translated and answered by proxy arp, without it only
+	/// `ipv4_local` is. Each address is mapped to the one at the same offset from `ipv6_local`,
+	/// so `ipv6_prefix` has to hold the whole range.
+	pub ipv4_prefix: Option<u8>,
+
+	/// Addresses in `ipv4_prefix` not translated and answered for, e.g. other hosts on the link
+	#[serde(default)]
+	pub ipv4_exclude: Vec<Ipv4Addr>,
+
+	/// Tags of the ipv4 side, untagged without `vlan`
+	#[serde(flatten)]
+	pub vlan: Vlan,
+}
+
+impl MapConfig {
+	/// Network and mask of `ipv4_prefix`, if it covers more than `ipv4_local`
+	fn ipv4_net(&self) -> Option<(u32, u32)> {
+		let prefix_len = self.ipv4_prefix.filter(|&prefix_len| prefix_len < 32)?;
+		let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+		Some((u32::from(self.ipv4_local) & mask, mask))
+	}
+
+	/// Whether arp requests for `addr` are answered by proxy arp for `ipv4_prefix`
+	fn proxies(&self, addr: Ipv4Addr) -> bool {
+		let (net, mask) = match self.ipv4_net() {
+			Some(net) => net,
+			None => return false,
+		};
+		let bits = u32::from(addr);
+		if bits & mask != net {
+			return false;
+		}
+		// network and broadcast address
+		if mask.count_ones() < 31 && (bits == net || bits == net | !mask) {
+			return false;
+		}
+
+		!self.ipv4_exclude.contains(&addr)
+	}
+
+	/// Ipv6 address `addr` is translated to, if it is `ipv4_local` or proxied
+	fn local_v6(&self, addr: Ipv4Addr) -> Option<Ipv6Addr> {
+		if addr == self.ipv4_local {
+			return Some(self.ipv6_local);
+		}
+		if !self.proxies(addr) {
+			return None;
+		}
+		let offset = i128::from(u32::from(addr)) - i128::from(u32::from(self.ipv4_local));
+		let local = u128::from(self.ipv6_local).wrapping_add(offset as u128);
+		Some(Ipv6Addr::from(local))
+	}
+
+	/// Ipv4 address translated to `addr`, the reverse of [`local_v6`](Self::local_v6)
+	fn local_v4(&self, addr: Ipv6Addr) -> Option<Ipv4Addr> {
+		if addr == self.ipv6_local {
+			return Some(self.ipv4_local);
+		}
+		self.ipv4_net()?;
+		let offset = u128::from(addr).wrapping_sub(u128::from(self.ipv6_local)) as i128;
+		let local = i128::from(u32::from(self.ipv4_local)).checked_add(offset)?;
+		let local = Ipv4Addr::from(u32::try_from(local).ok()?);
+		if self.proxies(local) {
+			Some(local)
+		} else {
+			None
+		}
+	}
+
+	/// Make sure `ipv6_prefix` holds all addresses of `ipv4_prefix`
+	fn check_prefixes(&self) -> Result<()> {
+		if self.ipv4_prefix.is_some_and(|prefix_len| prefix_len > 32) {
+			bail!("ipv4_prefix is longer than 32");
+		}
+		if self.ipv6_prefix.is_some_and(|prefix_len| prefix_len > 128) {
+			bail!("ipv6_prefix is longer than 128");
+		}
+		let (net, mask) = match self.ipv4_net() {
+			Some(net) => net,
+			None => return Ok(()),
+		};
+
+		let prefix_len = self.ipv6_prefix.unwrap_or(128);
+		let mask6 = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+		let local = u128::from(self.ipv6_local);
+		// offsets of the first and last address of the ipv4 prefix from ipv4_local
+		let below = u128::from(u32::from(self.ipv4_local) - net);
+		let above = u128::from((net | !mask) - u32::from(self.ipv4_local));
+		let fits = (local & !mask6) >= below && (!local & !mask6) >= above;
+		if !fits {
+			bail!(
+				"ipv6_prefix /{} around {} does not hold the ipv4_prefix /{} around {}",
+				prefix_len,
+				self.ipv6_local,
+				self.ipv4_prefix.unwrap_or(32),
+				self.ipv4_local
+			);
+		}
+
+		Ok(())
+	}
 }
 
 #[derive(Debug, Deserialize)]
@@ -111,6 +337,21 @@ pub struct Config {
 
 	#[serde(default)]
 	pub send_arp: bool,
+
+	/// Neighbor resolution on the ipv4 interface
+	#[serde(default)]
+	pub arp: ArpConfig,
+
+	/// Egress batching on both interfaces
+	#[serde(default)]
+	pub batch: BatchConfig,
+
+	/// Install routes for the mapped addresses on the ipv6 interface
+	pub routes: Option<RoutesConfig>,
+
+	/// Interval in seconds to log the counters, 0 disables it
+	#[serde(default)]
+	pub stats_interval: u64,
 }
 
 impl Config {
@@ -119,62 +360,475 @@ impl Config {
 			.await
 			.context("Reading config file")?;
 
-		Ok(serde_json::from_str(&json)?)
+		let config: Self = serde_json::from_str(&json)?;
+		config.check_prefixes()?;
+		config.check_vlans()?;
+		Ok(config)
 	}
 
-	pub async fn open_ipv6_stream(&self) -> Result<AsyncTunSocket> {
-		let ifcfg = &self.interfaces.ipv6;
+	fn check_prefixes(&self) -> Result<()> {
+		for mapping in &self.mappings {
+			mapping
+				.check_prefixes()
+				.with_context(|| format!("Mapping for {}", mapping.ipv4_local))?;
+		}
 
-		let socket = AsyncTunSocket::new(&ifcfg.name)?;
+		Ok(())
+	}
 
-		let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_DGRAM, libc::IPPROTO_IP) };
-		if fd < 0 {
-			return Err(std::io::Error::last_os_error().into());
+	fn check_vlans(&self) -> Result<()> {
+		for mapping in &self.mappings {
+			mapping
+				.vlan
+				.check()
+				.with_context(|| format!("Mapping for {}", mapping.ipv4_local))?;
 		}
-		let iptool = unsafe { IpTool::from_raw_fd(fd) };
-		if let Some(address) = ifcfg.address {
-			trace!("set address {} on interface {}", address, &ifcfg.name);
-			iptool.set_address(&ifcfg.name, &address, ifcfg.mask.unwrap_or(64))?;
-			iptool.set_up(&ifcfg.name, true)?;
+		for neighbor in &self.arp.static_neighbors {
+			neighbor
+				.vlan
+				.check()
+				.with_context(|| format!("Static neighbor {}", neighbor.address))?;
 		}
 
-		if ifcfg.mtu != 0 {
-			iptool.set_mtu(&ifcfg.name, ifcfg.mtu)?;
+		// the kernel takes the outer tag off and only hands it out of band
+		let tagged = self.mappings.iter().any(|m| m.vlan.is_tagged());
+		if tagged && self.interfaces.ipv4.backend == Ipv4Backend::Packet {
+			bail!("Mappings with a vlan need the ring, xdp or tap backend");
+		}
+		// the driver takes the tags off before the xdp program runs
+		let name = &self.interfaces.ipv4.name;
+		if tagged
+			&& self.interfaces.ipv4.backend == Ipv4Backend::Xdp
+			&& xdp::rx_vlan_offload(name)
+				.with_context(|| format!("Query vlan offloads of {}", name))?
+		{
+			bail!(
+				"Mappings with a vlan need rx vlan offload disabled on {} for the xdp backend, \
+				 e.g. ethtool -K {} rxvlan off rx-vlan-stag-hw-parse off",
+				name,
+				name
+			);
 		}
 
+		Ok(())
+	}
+
+	/// Open the tun, returns it together with whether it takes udp super packets
+	pub async fn open_ipv6_stream(&self) -> Result<(AsyncTunSocket, bool)> {
+		let ifcfg = &self.interfaces.ipv6;
+
+		if ifcfg.tap && ifcfg.offload {
+			bail!("Offloads are not supported on a tap device");
+		}
+
+		let options = TunOptions {
+			vnet_hdr: ifcfg.offload,
+			mode: if ifcfg.tap { Mode::Tap } else { Mode::Tun },
+			packet_info: ifcfg.packet_info,
+			persist: ifcfg.persist,
+			owner: ifcfg.owner,
+			group: ifcfg.group,
+		};
+		let socket = AsyncTunSocket::with_options(&ifcfg.name, &options)?;
+		let mut uso = false;
+		if ifcfg.offload {
+			// USO covers the udp traffic we translate, TSO only pays off once tcp is translated
+			let tso = offload::CSUM | offload::TSO4 | offload::TSO6 | offload::TSO_ECN;
+			match socket.set_offload(tso | offload::USO4 | offload::USO6) {
+				Ok(()) => uso = true,
+				Err(e) => {
+					// udp segmentation offload needs linux 6.2
+					debug!("udp segmentation offload not supported: {:?}", e);
+					socket.set_offload(tso).context("Enable tun offloads")?;
+				}
+			}
+		}
+
+		// no carrier until the translator is ready to forward
+		set_carrier(&socket, false);
+
+		let addresses = ifcfg.addresses();
+		configure_link(ifcfg, !addresses.is_empty(), addresses)?;
+
+		Ok((socket, uso))
+	}
+
+	pub async fn open_ipv4_stream(&self) -> Result<Ipv4Stream> {
+		let ifcfg = &self.interfaces.ipv4;
+
+		let socket = match ifcfg.backend {
+			Ipv4Backend::Packet => {
+				let mut socket = RawPacketStream::new()?;
+				filter::block(&socket)?;
+				socket.bind(&ifcfg.name)?;
+				socket.into()
+			}
+			Ipv4Backend::Ring => RingStream::new(&ifcfg.name, &ifcfg.ring)
+				.context("Open TPACKET_V3 socket")?
+				.into(),
+			Ipv4Backend::Xdp => XskStream::new(&ifcfg.name, &ifcfg.xdp)
+				.context("Open AF_XDP socket")?
+				.into(),
+			Ipv4Backend::Tap => {
+				let socket = AsyncTunSocket::new_tap(&ifcfg.name).context("Open tap device")?;
+				configure_link(ifcfg, true, ifcfg.addresses())?;
+				socket.into()
+			}
+		};
+
 		Ok(socket)
 	}
 
-	pub async fn open_ipv4_stream(&self) -> Result<RawPacketStream> {
+	/// Open one socket per configured queue, joined into a hash based fanout group
+	pub async fn open_ipv4_streams(&self) -> Result<Vec<Ipv4Stream>> {
 		let ifcfg = &self.interfaces.ipv4;
+		let queues = ifcfg.queues.max(1);
+
+		if queues == 1 {
+			return Ok(vec![self.open_ipv4_stream().await?]);
+		}
 
-		let mut socket = RawPacketStream::new()?;
+		if ifcfg.backend == Ipv4Backend::Xdp {
+			bail!("The xdp backend does not support multiple queues");
+		}
 
-		socket.bind(&ifcfg.name)?;
+		// a multi queue tap spreads the flows by itself
+		if ifcfg.backend == Ipv4Backend::Tap {
+			let mut sockets = Vec::with_capacity(queues as usize);
+			for _ in 0..queues {
+				sockets.push(self.open_ipv4_stream().await?);
+			}
+			return Ok(sockets);
+		}
 
-		// TODO: ebfp filter?
+		let group = ifcfg.fanout_group.unwrap_or(std::process::id() as u16);
+		trace!("joining {} sockets into fanout group {}", queues, group);
 
-		Ok(socket)
+		let mut sockets = Vec::with_capacity(queues as usize);
+		for _ in 0..queues {
+			let socket = self.open_ipv4_stream().await?;
+			packet::join_fanout(&socket, group)
+				.with_context(|| format!("Join fanout group {} on {}", group, &ifcfg.name))?;
+			sockets.push(socket);
+		}
+
+		Ok(sockets)
 	}
 
-	pub async fn run(self) -> Result<()> {
-		let ipv6 = self.open_ipv6_stream().await?;
+	/// Run the translator until a shutdown is requested, `filename` is reread on reload
+	pub async fn run(self, filename: &str) -> Result<()> {
+		let (ipv6, uso) = self.open_ipv6_stream().await?;
+
+		let ipv4 = self.open_ipv4_streams().await?;
+		let mut ipv4_mac = MacAddr::from_interface(&self.interfaces.ipv4.name)?;
+		if self.interfaces.ipv4.backend == Ipv4Backend::Tap {
+			ipv4_mac = tap::peer_mac(ipv4_mac);
+		}
 
-		let ipv4 = self.open_ipv4_stream().await?;
-		let ipv4_mac = MacAddr::from_interface(&self.interfaces.ipv4.name)?;
+		let tap_link = if self.interfaces.ipv6.tap {
+			let mac = MacAddr::from_interface(&self.interfaces.ipv6.name)?;
+			Some(TapLink::new(tap::peer_mac(mac)))
+		} else {
+			None
+		};
 
-		let arp_cache = ArpCache::new();
+		set_mappings(self.mappings);
 
-		// SAFETY: only caller at this point, we can write
-		unsafe { MAPPINGS = self.mappings };
+		update_filters(&ipv4)?;
 
-		let src_fut = src::tun_to_dst(ipv6.clone(), ipv4.clone(), ipv4_mac, arp_cache.clone());
-		let dst_fut = dst::dst_to_tun(ipv4, ipv6, arp_cache, ipv4_mac, self.send_arp);
+		let mut routes = match &self.routes {
+			Some(config) => {
+				let mut routes = RouteManager::new(&self.interfaces.ipv6.name, config)?;
+				routes.sync(&MAPPINGS.read().unwrap())?;
+				Some(routes)
+			}
+			None => None,
+		};
+		let filter_streams = ipv4.clone();
 
-		src_fut.try_join(dst_fut).await?;
+		if self.stats_interval != 0 {
+			async_std::task::spawn(stats::log_periodic(
+				Duration::from_secs(self.stats_interval),
+				ipv6.clone(),
+			));
+		}
+		let carrier = ipv6.clone();
 
-		todo!()
+		let ipv4_egress = BatchWriter::ipv4(ipv4[0].clone(), &self.batch);
+		let tun_egress = match &tap_link {
+			Some(link) => BatchWriter::tap(ipv6.clone(), link.clone(), &self.batch),
+			None => BatchWriter::tun(ipv6.clone(), uso, &self.batch),
+		};
+		let arp_cache = ArpCache::new(
+			&self.arp,
+			ipv4[0].clone(),
+			ipv4_egress,
+			tun_egress.clone(),
+			ipv4_mac,
+			self.send_arp,
+		);
+		if let Some(path) = &self.arp.persist {
+			if let Err(e) = arp_cache.load(path).await {
+				warn!("failed to load the arp cache: {:?}", e);
+			}
+		}
+
+		let src_fut = src::tun_to_dst(ipv6, arp_cache.clone(), tap_link);
+
+		// one receive loop per fanout member, the first one to fail ends the run
+		let dst_fut = ipv4
+			.into_iter()
+			.map(|ipv4| {
+				let fut = dst::dst_to_tun(ipv4, tun_egress.clone(), arp_cache.clone());
+				Box::pin(async_std::task::spawn(fut)) as LoopFuture
+			})
+			.reduce(|a, b| Box::pin(a.race(b)))
+			.context("No ipv4 socket opened")?;
+
+		let claim = arp_cache.clone();
+		async_std::task::spawn(async move { claim.claim(&local_ipv4_addrs()).await });
+		async_std::task::spawn(watch_mac(
+			self.interfaces.ipv4.name.clone(),
+			self.interfaces.ipv4.backend == Ipv4Backend::Tap,
+			arp_cache.clone(),
+		));
+
+		let loops = async { src_fut.try_join(dst_fut).await.map(|_| ()) };
+		set_carrier(&carrier, true);
+		let res = loops
+			.race(control(filename, &filter_streams, &mut routes, &arp_cache))
+			.await;
+		set_carrier(&carrier, false);
+
+		if let Some(path) = &self.arp.persist {
+			if let Err(e) = arp_cache.save(path).await {
+				warn!("failed to save the arp cache: {:?}", e);
+			}
+		}
+
+		if let Some(routes) = &mut routes {
+			if let Err(e) = routes.remove_all() {
+				warn!("failed to remove routes: {:?}", e);
+			}
+		}
+
+		res
+	}
+}
+
+/// Handle reload, dump and shutdown requests, returns on shutdown
+async fn control(
+	filename: &str,
+	streams: &[Ipv4Stream],
+	routes: &mut Option<RouteManager>,
+	arp_cache: &ArpCache,
+) -> Result<()> {
+	let events = control::events()?;
+
+	while let Ok(event) = events.recv().await {
+		match event {
+			Event::Reload => {
+				info!("reloading mappings from {}", filename);
+				if let Err(e) = reload(filename, streams, routes, arp_cache).await {
+					warn!("reload failed: {:?}", e);
+				}
+			}
+			Event::DumpArp => {
+				let dump: Vec<String> = arp_cache
+					.dump()
+					.await
+					.iter()
+					.map(ToString::to_string)
+					.collect();
+				info!("arp cache:\n{}", dump.join("\n"));
+			}
+			Event::Shutdown => {
+				info!("shutting down");
+				break;
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// Replace the mappings, only the mappings of the config file are applied
+async fn reload(
+	filename: &str,
+	streams: &[Ipv4Stream],
+	routes: &mut Option<RouteManager>,
+	arp_cache: &ArpCache,
+) -> Result<()> {
+	let config = Config::parse_file(filename).await?;
+	let old = local_ipv4_addrs();
+	set_mappings(config.mappings);
+
+	update_filters(streams)?;
+	if let Some(routes) = routes {
+		routes.sync(&MAPPINGS.read().unwrap())?;
+	}
+
+	let new = local_ipv4_addrs();
+	let removed: Vec<VlanAddr> = old
+		.iter()
+		.filter(|addr| !new.contains(addr))
+		.copied()
+		.collect();
+	arp_cache.release(&removed);
+
+	// addresses given up after a conflict are probed for again
+	let mut added: Vec<VlanAddr> = new
+		.iter()
+		.filter(|addr| !old.contains(addr))
+		.copied()
+		.collect();
+	added.extend(arp_cache.conflicts(&new));
+	let arp_cache = arp_cache.clone();
+	async_std::task::spawn(async move { arp_cache.claim(&added).await });
+
+	Ok(())
+}
+
+/// Poll the hardware address of the ipv4 interface, our addresses are announced again when it
+/// changes
+async fn watch_mac(name: String, tap: bool, arp_cache: ArpCache) {
+	loop {
+		async_std::task::sleep(MAC_POLL_INTERVAL).await;
+
+		let mac = match MacAddr::from_interface(&name) {
+			Ok(mac) if tap => tap::peer_mac(mac),
+			Ok(mac) => mac,
+			Err(e) => {
+				warn!("failed to read hardware address of {}: {:?}", name, e);
+				continue;
+			}
+		};
+		if mac == arp_cache.mac() {
+			continue;
+		}
+
+		info!("hardware address of {} changed to {}", name, mac);
+		arp_cache.set_mac(mac);
+		arp_cache.announce(&local_ipv4_addrs()).await;
+	}
+}
+
+/// Replace the mappings, cached lookups of the old ones are not used anymore
+fn set_mappings(mappings: Vec<MapConfig>) {
+	let mut guard = MAPPINGS.write().unwrap();
+	*guard = mappings;
+	// while holding the lock, whoever sees the new generation sees the new mappings as well
+	GENERATION.fetch_add(1, Ordering::Release);
+}
+
+fn generation() -> u64 {
+	GENERATION.load(Ordering::Acquire)
+}
+
+/// Apply link state, MTU, txqueuelen and addresses of `ifcfg` in one netlink batch
+fn configure_link(ifcfg: &InterfaceConfig, up: bool, addresses: Vec<Address>) -> Result<()> {
+	let config = LinkConfig {
+		up: if up { Some(true) } else { None },
+		mtu: if ifcfg.mtu != 0 {
+			Some(ifcfg.mtu)
+		} else {
+			None
+		},
+		txqueuelen: ifcfg.txqueuelen,
+		addresses,
+		..Default::default()
+	};
+	trace!("configure interface {}: {:?}", &ifcfg.name, config);
+
+	let index = Netlink::link_index(&ifcfg.name)?;
+	Netlink::new()?
+		.configure(index, &config)
+		.with_context(|| format!("Configure interface {}", &ifcfg.name))?;
+
+	Ok(())
+}
+
+/// Toggle the carrier of the tun, older kernels do not support this so it is not fatal
+fn set_carrier(tun: &AsyncTunSocket, carrier: bool) {
+	trace!("set tun carrier {}", carrier);
+	if let Err(e) = tun.set_carrier(carrier) {
+		warn!("failed to set tun carrier: {:?}", e);
+	}
+}
+
+/// Distinct `ipv4_local` addresses of all mappings, with the vlan they are served on
+fn local_ipv4_addrs() -> Vec<VlanAddr> {
+	let mut addrs: Vec<VlanAddr> = MAPPINGS
+		.read()
+		.unwrap()
+		.iter()
+		.map(|m| VlanAddr::new(m.vlan, m.ipv4_local))
+		.collect();
+	addrs.sort_unstable();
+	addrs.dedup();
+	addrs
+}
+
+/// Regenerate the socket filter from the mappings and attach it to all ipv4 sockets
+pub fn update_filters(streams: &[Ipv4Stream]) -> Result<()> {
+	let rules = filter_rules(&MAPPINGS.read().unwrap());
+	let prog = filter::build(&rules)?;
+	trace!(
+		"socket filter for {} rules: {} instructions",
+		rules.len(),
+		prog.len()
+	);
+
+	for stream in streams {
+		stream
+			.set_filter(&prog, &rules)
+			.context("Attach socket filter")?;
+	}
+
+	Ok(())
+}
+
+/// Destinations translated by `mappings`: `ipv4_local` and the `ipv4_prefix` without the
+/// addresses which are not proxied
+fn filter_rules(mappings: &[MapConfig]) -> Vec<filter::Rule> {
+	let mut rules = Vec::new();
+	for mapping in mappings {
+		rules.push(filter::Rule::new(
+			mapping.vlan,
+			mapping.ipv4_local,
+			32,
+			true,
+		));
+		let (net, mask) = match mapping.ipv4_net() {
+			Some(net) => net,
+			None => continue,
+		};
+		let prefix_len = mask.count_ones() as u8;
+		rules.push(filter::Rule::new(
+			mapping.vlan,
+			mapping.ipv4_local,
+			prefix_len,
+			true,
+		));
+
+		let mut refused = mapping.ipv4_exclude.clone();
+		if prefix_len < 31 {
+			refused.push(Ipv4Addr::from(net));
+			refused.push(Ipv4Addr::from(net | !mask));
+		}
+		for addr in refused {
+			// another mapping on the vlan might still translate it
+			let translated = mappings
+				.iter()
+				.any(|m| m.vlan == mapping.vlan && m.local_v6(addr).is_some());
+			if !translated {
+				rules.push(filter::Rule::new(mapping.vlan, addr, 32, false));
+			}
+		}
 	}
+	rules.sort_unstable();
+	rules.dedup();
+	rules
 }
 
 pub fn supports(proto: IpNextHeaderProtocol) -> Result<()> {
@@ -190,22 +844,41 @@ pub struct MapResult {
 	pub src: Ipv4Addr,
 	pub dst: Ipv4Addr,
 	pub gw: Option<Ipv4Addr>,
+	pub vlan: Vlan,
 }
 
 impl MapResult {
 	#[inline(always)]
 	pub fn find_v6(src: Ipv6Addr, dst: Ipv6Addr) -> Option<MapResult> {
-		find_v6_cached(src, dst)
+		find_v6_cached(generation(), src, dst)
 	}
 
 	#[inline(always)]
-	pub fn find_v4(src: Ipv4Addr, dst: Ipv4Addr) -> Option<(Ipv6Addr, Ipv6Addr)> {
-		find_v4_cached(dst, src)
+	pub fn find_v4(src: Ipv4Addr, dst: Ipv4Addr, vlan: Vlan) -> Option<(Ipv6Addr, Ipv6Addr)> {
+		find_v4_cached(generation(), dst, src, vlan)
 	}
 
 	#[inline(always)]
-	pub fn find_v4_by_local(dst: Ipv4Addr) -> Option<()> {
-		find_v4_by_local_cached(dst)
+	pub fn find_v6_by_local(addr: Ipv6Addr) -> Option<()> {
+		find_v6_by_local_cached(generation(), addr)
+	}
+
+	#[inline(always)]
+	pub fn find_v4_by_local(dst: Ipv4Addr, vlan: Vlan) -> Option<()> {
+		find_v4_by_local_cached(generation(), dst, vlan)
+	}
+
+	/// Not cached, every address of a proxied prefix could be asked for
+	pub fn find_v4_proxy(addr: Ipv4Addr, vlan: Vlan) -> Option<()> {
+		let mappings = MAPPINGS.read().unwrap();
+
+		for mapping in mappings.iter() {
+			if mapping.vlan == vlan && mapping.proxies(addr) {
+				return Some(());
+			}
+		}
+
+		None
 	}
 
 	/*#[cached(size = 20)]
@@ -215,38 +888,62 @@ impl MapResult {
 }
 
 #[cached(size = 20)]
-fn find_v6_cached(dst: Ipv6Addr, src: Ipv6Addr) -> Option<MapResult> {
-	// SAFETY: only reading and after the only write
-	let mappings = unsafe { &MAPPINGS };
+fn find_v6_cached(_generation: u64, dst: Ipv6Addr, src: Ipv6Addr) -> Option<MapResult> {
+	let mappings = MAPPINGS.read().unwrap();
 
-	for mapping in mappings {
-		if mapping.ipv6_local == src && mapping.ipv6_remote == dst {
-			return Some(mapping.into());
+	for mapping in mappings.iter() {
+		if mapping.ipv6_remote != dst {
+			continue;
+		}
+		if let Some(local) = mapping.local_v4(src) {
+			return Some(MapResult {
+				src: local,
+				..mapping.into()
+			});
 		}
 	}
 	None
 }
 
 #[cached(size = 20)]
-fn find_v4_cached(dst: Ipv4Addr, src: Ipv4Addr) -> Option<(Ipv6Addr, Ipv6Addr)> {
-	// SAFETY: only reading and after the only write
-	let mappings = unsafe { &MAPPINGS };
+fn find_v4_cached(
+	_generation: u64,
+	dst: Ipv4Addr,
+	src: Ipv4Addr,
+	vlan: Vlan,
+) -> Option<(Ipv6Addr, Ipv6Addr)> {
+	let mappings = MAPPINGS.read().unwrap();
 
-	for mapping in mappings {
-		if mapping.ipv4_local == dst && mapping.ipv4_remote == src {
-			return Some((mapping.ipv6_local, mapping.ipv6_remote));
+	for mapping in mappings.iter() {
+		if mapping.ipv4_remote != src || mapping.vlan != vlan {
+			continue;
+		}
+		if let Some(local) = mapping.local_v6(dst) {
+			return Some((local, mapping.ipv6_remote));
 		}
 	}
 	None
 }
 
 #[cached(size = 20)]
-fn find_v4_by_local_cached(addr: Ipv4Addr) -> Option<()> {
-	// SAFETY: only reading and after the only write
-	let mappings = unsafe { &MAPPINGS };
+fn find_v6_by_local_cached(_generation: u64, addr: Ipv6Addr) -> Option<()> {
+	let mappings = MAPPINGS.read().unwrap();
 
-	for mapping in mappings {
-		if mapping.ipv4_local == addr {
+	for mapping in mappings.iter() {
+		if mapping.local_v4(addr).is_some() {
+			return Some(());
+		}
+	}
+
+	None
+}
+
+#[cached(size = 20)]
+fn find_v4_by_local_cached(_generation: u64, addr: Ipv4Addr, vlan: Vlan) -> Option<()> {
+	let mappings = MAPPINGS.read().unwrap();
+
+	for mapping in mappings.iter() {
+		if mapping.ipv4_local == addr && mapping.vlan == vlan {
 			return Some(());
 		}
 	}
@@ -260,6 +957,146 @@ impl<'a> From<&'a MapConfig> for MapResult {
 			src: mapping.ipv4_local,
 			dst: mapping.ipv4_remote,
 			gw: mapping.ipv4_gateway,
+			vlan: mapping.vlan,
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn map_config(ipv4: &str, ipv6: &str, extra: &str) -> MapConfig {
+		let (ipv4_local, ipv4_prefix) = ipv4.split_once('/').unwrap_or((ipv4, "null"));
+		let (ipv6_local, ipv6_prefix) = ipv6.split_once('/').unwrap_or((ipv6, "null"));
+		let json = format!(
+			r#"{{"ipv4_local": "{}", "ipv4_prefix": {}, "ipv4_remote": "198.51.100.1",
+			"ipv6_local": "{}", "ipv6_prefix": {}, "ipv6_remote": "2001:db8::1" {}}}"#,
+			ipv4_local, ipv4_prefix, ipv6_local, ipv6_prefix, extra
+		);
+		serde_json::from_str(&json).unwrap()
+	}
+
+	fn proxied(mapping: &MapConfig, net: [u8; 3]) -> Vec<u8> {
+		(0..=255)
+			.filter(|host| mapping.proxies(Ipv4Addr::new(net[0], net[1], net[2], *host)))
+			.collect()
+	}
+
+	#[test]
+	fn proxies_prefix() {
+		let mapping = map_config("192.0.2.1/24", "64:ff9b::c000:201/120", "");
+		let proxied = proxied(&mapping, [192, 0, 2]);
+		// without network and broadcast address
+		assert_eq!(proxied, (1..=254).collect::<Vec<u8>>());
+		assert!(!mapping.proxies(Ipv4Addr::new(192, 0, 3, 1)));
+	}
+
+	#[test]
+	fn proxies_edge_prefixes() {
+		// both addresses of a point to point link
+		let mapping = map_config("192.0.2.1/31", "64:ff9b::c000:201/127", "");
+		assert_eq!(proxied(&mapping, [192, 0, 2]), [0, 1]);
+
+		let mapping = map_config("192.0.2.1/32", "64:ff9b::c000:201", "");
+		assert!(proxied(&mapping, [192, 0, 2]).is_empty());
+
+		let mapping = map_config("192.0.2.1", "64:ff9b::c000:201", "");
+		assert!(proxied(&mapping, [192, 0, 2]).is_empty());
+
+		let mapping = map_config("192.0.2.1/0", "::c000:201/96", "");
+		assert!(mapping.proxies(Ipv4Addr::new(203, 0, 113, 9)));
+		assert!(!mapping.proxies(Ipv4Addr::new(0, 0, 0, 0)));
+		assert!(!mapping.proxies(Ipv4Addr::BROADCAST));
+	}
+
+	#[test]
+	fn proxies_exclude() {
+		let extra = r#", "ipv4_exclude": ["192.0.2.7", "192.0.3.7"]"#;
+		let mapping = map_config("192.0.2.1/29", "64:ff9b::c000:201/125", extra);
+		assert_eq!(proxied(&mapping, [192, 0, 2]), [1, 2, 3, 4, 5, 6]);
+	}
+
+	#[test]
+	fn translate_prefix() {
+		let mapping = map_config("192.0.2.10/24", "2001:db8::a/120", "");
+		let v4 = |addr: &str| addr.parse::<Ipv4Addr>().unwrap();
+		let v6 = |addr: &str| addr.parse::<Ipv6Addr>().unwrap();
+
+		assert_eq!(mapping.local_v6(v4("192.0.2.10")), Some(v6("2001:db8::a")));
+		assert_eq!(mapping.local_v6(v4("192.0.2.1")), Some(v6("2001:db8::1")));
+		assert_eq!(
+			mapping.local_v6(v4("192.0.2.254")),
+			Some(v6("2001:db8::fe"))
+		);
+		assert_eq!(mapping.local_v6(v4("192.0.2.255")), None);
+		assert_eq!(mapping.local_v6(v4("192.0.3.10")), None);
+
+		assert_eq!(mapping.local_v4(v6("2001:db8::a")), Some(v4("192.0.2.10")));
+		assert_eq!(
+			mapping.local_v4(v6("2001:db8::fe")),
+			Some(v4("192.0.2.254"))
+		);
+		assert_eq!(mapping.local_v4(v6("2001:db8::")), None);
+		assert_eq!(mapping.local_v4(v6("2001:db8::100")), None);
+		assert_eq!(mapping.local_v4(v6("2001:db9::a")), None);
+	}
+
+	#[test]
+	fn translate_without_prefix() {
+		let mapping = map_config("192.0.2.10", "2001:db8::a/120", "");
+		let local = mapping.local_v6(Ipv4Addr::new(192, 0, 2, 10));
+		assert_eq!(local, Some(mapping.ipv6_local));
+		assert_eq!(mapping.local_v6(Ipv4Addr::new(192, 0, 2, 11)), None);
+		assert_eq!(mapping.local_v4("2001:db8::b".parse().unwrap()), None);
+	}
+
+	#[test]
+	fn check_prefixes() {
+		assert!(map_config("192.0.2.10/24", "2001:db8::a/120", "")
+			.check_prefixes()
+			.is_ok());
+		assert!(map_config("192.0.2.1/31", "2001:db8::1/127", "")
+			.check_prefixes()
+			.is_ok());
+		assert!(map_config("192.0.2.1", "2001:db8::1", "")
+			.check_prefixes()
+			.is_ok());
+		// the offsets of ipv4_local and ipv6_local in their prefixes differ
+		assert!(map_config("192.0.2.10/24", "2001:db8::10/120", "")
+			.check_prefixes()
+			.is_err());
+		assert!(map_config("192.0.2.10/24", "2001:db8::a/121", "")
+			.check_prefixes()
+			.is_err());
+		assert!(map_config("192.0.2.1/33", "2001:db8::1", "")
+			.check_prefixes()
+			.is_err());
+	}
+
+	#[test]
+	fn filter_rules_prefix() {
+		let extra = r#", "ipv4_exclude": ["192.0.2.5", "192.0.2.6"]"#;
+		let mappings = [
+			map_config("192.0.2.1/24", "2001:db8::1/120", extra),
+			// translated by another mapping
+			map_config("192.0.2.5", "2001:db8:1::5", ""),
+		];
+		let untagged = Vlan::default();
+		let rule = |addr: [u8; 4], prefix_len, accept| {
+			filter::Rule::new(untagged, addr.into(), prefix_len, accept)
+		};
+
+		assert_eq!(
+			filter_rules(&mappings),
+			[
+				rule([192, 0, 2, 0], 24, true),
+				rule([192, 0, 2, 0], 32, false),
+				rule([192, 0, 2, 1], 32, true),
+				rule([192, 0, 2, 5], 32, true),
+				rule([192, 0, 2, 6], 32, false),
+				rule([192, 0, 2, 255], 32, false),
+			]
+		);
+	}
+}
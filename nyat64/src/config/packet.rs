@@ -0,0 +1,71 @@
+use std::io::{Error as IoError, Result as IoResult};
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{Context, Result};
+use nix::libc;
+
+/// Join the socket into the PACKET_FANOUT group `group`.
+///
+/// All members of a group have to be bound to the same interface, the kernel then
+/// distributes received frames by flow hash, so a flow always ends up on the same socket.
+pub fn join_fanout(socket: &impl AsRawFd, group: u16) -> Result<()> {
+	let arg: libc::c_int =
+		group as libc::c_int | ((PACKET_FANOUT_HASH | PACKET_FANOUT_FLAG_DEFRAG) << 16);
+
+	// SAFETY: call to c function, fd is valid while socket is valid, arg lives on the stack
+	let res = unsafe {
+		libc::setsockopt(
+			socket.as_raw_fd(),
+			libc::SOL_PACKET,
+			PACKET_FANOUT,
+			&arg as *const _ as _,
+			size_of::<libc::c_int>() as _,
+		)
+	};
+	if res < 0 {
+		return Err(IoError::last_os_error()).context("setsockopt PACKET_FANOUT");
+	}
+
+	Ok(())
+}
+
+/// Send `frames` with a single sendmmsg call without blocking, returns the number of frames sent
+pub fn send_frames(socket: &impl AsRawFd, frames: &[Vec<u8>]) -> IoResult<usize> {
+	let mut iovs: Vec<libc::iovec> = frames
+		.iter()
+		.map(|frame| libc::iovec {
+			iov_base: frame.as_ptr() as *mut _,
+			iov_len: frame.len(),
+		})
+		.collect();
+
+	let mut msgs: Vec<libc::mmsghdr> = iovs
+		.iter_mut()
+		.map(|iov| {
+			// SAFETY: all zero is a valid mmsghdr
+			let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+			msg.msg_hdr.msg_iov = iov;
+			msg.msg_hdr.msg_iovlen = 1;
+			msg
+		})
+		.collect();
+
+	// SAFETY: call to c function, msgs and the iovecs point into frames, which outlive the call
+	match unsafe {
+		libc::sendmmsg(
+			socket.as_raw_fd(),
+			msgs.as_mut_ptr(),
+			msgs.len() as _,
+			libc::MSG_DONTWAIT as _,
+		)
+	} {
+		-1 => Err(IoError::last_os_error()),
+		n => Ok(n as usize),
+	}
+}
+
+// libc helpers not defined in libc
+const PACKET_FANOUT: libc::c_int = 18;
+const PACKET_FANOUT_HASH: libc::c_int = 0;
+const PACKET_FANOUT_FLAG_DEFRAG: libc::c_int = 0x8000;
@@ -0,0 +1,36 @@
+use std::time::Instant;
+
+/// Token bucket, refilled with `rate` tokens per second up to `burst`
+#[derive(Debug)]
+pub struct RateLimit {
+	rate: f64,
+	burst: f64,
+	tokens: f64,
+	last: Instant,
+}
+
+impl RateLimit {
+	pub fn new(rate: u32, burst: u32) -> Self {
+		let burst = burst.max(1) as f64;
+		Self {
+			rate: rate as f64,
+			burst,
+			tokens: burst,
+			last: Instant::now(),
+		}
+	}
+
+	/// Take a token, returns false if the limit is exceeded
+	pub fn check(&mut self) -> bool {
+		let now = Instant::now();
+		let elapsed = now.duration_since(self.last).as_secs_f64();
+		self.last = now;
+		self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+
+		if self.tokens < 1.0 {
+			return false;
+		}
+		self.tokens -= 1.0;
+		true
+	}
+}
@@ -0,0 +1,510 @@
+use std::ffi::CString;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::{bail, Context, Result};
+use async_io::Async;
+use async_std::io::{Read, Write};
+use log::*;
+use nix::libc;
+use serde::Deserialize;
+
+use crate::config::stats::STATS;
+use crate::config::{filter, vlan};
+
+/// Sizes of the TPACKET_V3 rings
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RingConfig {
+	/// Size of a ring block, has to be a multiple of the page size
+	pub block_size: u32,
+	/// Number of receive blocks
+	pub rx_blocks: u32,
+	/// Number of transmit blocks
+	pub tx_blocks: u32,
+	/// Size of a transmit frame, has to hold the largest frame plus the header
+	pub frame_size: u32,
+	/// Time in ms after which the kernel hands a partially filled block to us
+	pub block_timeout: u32,
+}
+
+impl Default for RingConfig {
+	fn default() -> Self {
+		Self {
+			block_size: 1 << 20,
+			rx_blocks: 16,
+			tx_blocks: 4,
+			frame_size: 2048,
+			block_timeout: 10,
+		}
+	}
+}
+
+/// Position inside of the receive ring
+#[derive(Debug, Default)]
+struct RxCursor {
+	block: usize,
+	/// offset of the next packet inside of the current block, if the block is owned by us
+	packet: Option<usize>,
+	/// packets left in the current block
+	remaining: u32,
+}
+
+/// AF_PACKET socket with mmaped TPACKET_V3 receive and transmit rings
+#[derive(Debug)]
+pub struct RingSocket {
+	fd: RawFd,
+	map: *mut u8,
+	rx_size: usize,
+	tx_size: usize,
+	block_size: usize,
+	rx_blocks: usize,
+	frame_size: usize,
+	tx_frames: usize,
+
+	rx: Mutex<RxCursor>,
+	tx: Mutex<usize>,
+}
+
+// SAFETY: the mapping is only accessed through the cursors, which are guarded by a mutex
+unsafe impl Send for RingSocket {}
+unsafe impl Sync for RingSocket {}
+
+impl RingSocket {
+	pub fn new(name: &str, config: &RingConfig) -> Result<Self> {
+		if (config.frame_size as usize) < TPACKET3_HDRLEN || !config.frame_size.is_multiple_of(16) {
+			bail!("Invalid ring frame size: {}", config.frame_size);
+		}
+		if config.block_size == 0 || !config.block_size.is_multiple_of(config.frame_size) {
+			bail!("Ring block size has to be a multiple of the frame size");
+		}
+		// SAFETY: call to c function without side effects
+		let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+		if !config.block_size.is_multiple_of(page_size) {
+			bail!(
+				"Ring block size has to be a multiple of the page size ({})",
+				page_size
+			);
+		}
+		if config.rx_blocks == 0 || config.tx_blocks == 0 {
+			bail!("Ring needs at least one rx and one tx block");
+		}
+
+		// SAFETY: call to c function, parameters are const
+		let fd = match unsafe {
+			libc::socket(
+				libc::AF_PACKET,
+				libc::SOCK_RAW,
+				(libc::ETH_P_ALL as u16).to_be() as _,
+			)
+		} {
+			-1 => return Err(IoError::last_os_error()).context("Open packet socket"),
+			fd => fd,
+		};
+
+		// build the struct before setting it up, so drop cleans up on errors
+		let mut socket = Self {
+			fd,
+			map: ptr::null_mut(),
+			rx_size: config.block_size as usize * config.rx_blocks as usize,
+			tx_size: config.block_size as usize * config.tx_blocks as usize,
+			block_size: config.block_size as usize,
+			rx_blocks: config.rx_blocks as usize,
+			frame_size: config.frame_size as usize,
+			tx_frames: (config.block_size / config.frame_size * config.tx_blocks) as usize,
+			rx: Mutex::new(RxCursor::default()),
+			tx: Mutex::new(0),
+		};
+
+		filter::block(&socket)?;
+		setsockopt(fd, PACKET_VERSION, &TPACKET_V3).context("Set TPACKET_V3")?;
+
+		let rx_req = TpacketReq3 {
+			tp_block_size: config.block_size,
+			tp_block_nr: config.rx_blocks,
+			tp_frame_size: config.frame_size,
+			tp_frame_nr: config.block_size / config.frame_size * config.rx_blocks,
+			tp_retire_blk_tov: config.block_timeout,
+			tp_sizeof_priv: 0,
+			tp_feature_req_word: 0,
+		};
+		setsockopt(fd, PACKET_RX_RING, &rx_req).context("Set up rx ring")?;
+
+		// the kernel only supports frame based transmit rings
+		let tx_req = TpacketReq3 {
+			tp_block_size: config.block_size,
+			tp_block_nr: config.tx_blocks,
+			tp_frame_size: config.frame_size,
+			tp_frame_nr: socket.tx_frames as _,
+			tp_retire_blk_tov: 0,
+			tp_sizeof_priv: 0,
+			tp_feature_req_word: 0,
+		};
+		setsockopt(fd, PACKET_TX_RING, &tx_req).context("Set up tx ring")?;
+
+		// SAFETY: call to c function, fd is valid and the rings are set up with these sizes
+		socket.map = match unsafe {
+			libc::mmap(
+				ptr::null_mut(),
+				socket.rx_size + socket.tx_size,
+				libc::PROT_READ | libc::PROT_WRITE,
+				libc::MAP_SHARED | libc::MAP_POPULATE,
+				fd,
+				0,
+			)
+		} {
+			libc::MAP_FAILED => return Err(IoError::last_os_error()).context("mmap rings"),
+			map => map as *mut u8,
+		};
+
+		let iface_name = CString::new(name).context("Invalid interface name")?;
+		// SAFETY: call to c function, iface_name is a valid c string
+		let ifindex = match unsafe { libc::if_nametoindex(iface_name.as_ptr()) } {
+			0 => return Err(IoError::last_os_error()).context("Get interface index"),
+			index => index,
+		};
+
+		// SAFETY: all zero is a valid sockaddr_ll
+		let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+		addr.sll_family = libc::AF_PACKET as _;
+		addr.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+		addr.sll_ifindex = ifindex as _;
+
+		// SAFETY: call to c function, addr is a valid sockaddr_ll
+		if unsafe {
+			libc::bind(
+				fd,
+				&addr as *const _ as _,
+				size_of::<libc::sockaddr_ll>() as _,
+			)
+		} < 0
+		{
+			return Err(IoError::last_os_error()).context("Bind packet socket");
+		}
+
+		Ok(socket)
+	}
+
+	/// Copy the next received frame into `buf`.
+	///
+	/// Frames are taken out of the current block in order, the block is only returned to the
+	/// kernel once all of its frames are read.
+	pub fn recv(&self, buf: &mut [u8]) -> IoResult<usize> {
+		let mut rx = self.rx.lock().unwrap();
+
+		loop {
+			let block = self.rx_block(rx.block);
+
+			let offset = match rx.packet {
+				Some(offset) => offset,
+				None => {
+					// SAFETY: block points to a block descriptor inside of the rx ring
+					let status = unsafe { ptr::read_volatile(&(*block).block_status) };
+					if status & TP_STATUS_USER == 0 {
+						return Err(ErrorKind::WouldBlock.into());
+					}
+					fence(Ordering::Acquire);
+
+					// SAFETY: the block is owned by us
+					unsafe {
+						rx.remaining = (*block).num_pkts;
+						(*block).offset_to_first_pkt as usize
+					}
+				}
+			};
+
+			if rx.remaining == 0 {
+				self.release_block(&mut rx);
+				continue;
+			}
+
+			// SAFETY: offset is inside of the block, as reported by the kernel
+			let (hdr, addr) = unsafe {
+				let hdr = (block as *mut u8).add(offset) as *const Tpacket3Hdr;
+				let addr = (hdr as *const u8).add(TPACKET3_HDRLEN - size_of::<libc::sockaddr_ll>())
+					as *const libc::sockaddr_ll;
+				(&*hdr, &*addr)
+			};
+
+			rx.remaining -= 1;
+			rx.packet = Some(offset + hdr.tp_next_offset as usize);
+
+			// frames sent by ourselves are looped back into the ring as well
+			if addr.sll_pkttype == PACKET_OUTGOING {
+				continue;
+			}
+
+			let len = (hdr.tp_snaplen as usize).min(buf.len());
+			// SAFETY: tp_mac and tp_snaplen describe the frame inside of the block
+			let frame = unsafe {
+				std::slice::from_raw_parts((hdr as *const _ as *const u8).add(hdr.tp_mac as _), len)
+			};
+			buf[..len].copy_from_slice(frame);
+
+			// the kernel takes the outer vlan tag off, put it back for the dst loop
+			if hdr.tp_status & TP_STATUS_VLAN_VALID != 0 {
+				let tpid = if hdr.tp_status & TP_STATUS_VLAN_TPID_VALID != 0 {
+					hdr.hv1_vlan_tpid
+				} else {
+					vlan::ETH_P_8021Q
+				};
+				match vlan::insert_tag(buf, len, tpid, hdr.hv1_vlan_tci as u16) {
+					Some(len) => return Ok(len),
+					None => {
+						STATS.vlan_tag_drops.inc();
+						continue;
+					}
+				}
+			}
+
+			return Ok(len);
+		}
+	}
+
+	/// Queue `buf` in the transmit ring, without notifying the kernel
+	pub fn queue(&self, buf: &[u8]) -> IoResult<()> {
+		if buf.len() > self.frame_size - TX_DATA_OFFSET {
+			return Err(IoError::new(
+				ErrorKind::InvalidInput,
+				"frame too big for ring",
+			));
+		}
+
+		let mut tx = self.tx.lock().unwrap();
+		let hdr = self.tx_frame(*tx);
+
+		// SAFETY: hdr points to a frame inside of the tx ring
+		let status = unsafe { ptr::read_volatile(&(*hdr).tp_status) };
+		match status {
+			TP_STATUS_AVAILABLE => (),
+			TP_STATUS_WRONG_FORMAT => {
+				warn!("kernel rejected a frame in the tx ring");
+			}
+			_ => return Err(ErrorKind::WouldBlock.into()),
+		}
+
+		// SAFETY: the frame is owned by us and big enough, checked above
+		unsafe {
+			let data = (hdr as *mut u8).add(TX_DATA_OFFSET);
+			ptr::copy_nonoverlapping(buf.as_ptr(), data, buf.len());
+			(*hdr).tp_len = buf.len() as _;
+			(*hdr).tp_snaplen = buf.len() as _;
+			(*hdr).tp_next_offset = 0;
+			fence(Ordering::Release);
+			ptr::write_volatile(&mut (*hdr).tp_status, TP_STATUS_SEND_REQUEST);
+		}
+
+		*tx = (*tx + 1) % self.tx_frames;
+
+		Ok(())
+	}
+
+	/// Ask the kernel to send all queued frames
+	pub fn flush(&self) -> IoResult<()> {
+		// SAFETY: call to c function, an empty send kicks the tx ring
+		match unsafe { libc::send(self.fd, ptr::null(), 0, libc::MSG_DONTWAIT) } {
+			-1 => match IoError::last_os_error() {
+				e if e.kind() == ErrorKind::WouldBlock => Ok(()),
+				e => Err(e),
+			},
+			_ => Ok(()),
+		}
+	}
+
+	pub fn send(&self, buf: &[u8]) -> IoResult<usize> {
+		self.queue(buf)?;
+		self.flush()?;
+		Ok(buf.len())
+	}
+
+	fn release_block(&self, rx: &mut RxCursor) {
+		let block = self.rx_block(rx.block);
+		fence(Ordering::Release);
+		// SAFETY: the block is owned by us, handing it back to the kernel
+		unsafe { ptr::write_volatile(&mut (*block).block_status, TP_STATUS_KERNEL) };
+
+		rx.block = (rx.block + 1) % self.rx_blocks;
+		rx.packet = None;
+		rx.remaining = 0;
+	}
+
+	fn rx_block(&self, block: usize) -> *mut TpacketBlockDesc {
+		// SAFETY: block is always smaller than rx_blocks
+		unsafe { self.map.add(block * self.block_size) as *mut TpacketBlockDesc }
+	}
+
+	fn tx_frame(&self, frame: usize) -> *mut Tpacket3Hdr {
+		// frames do not cross block boundaries, block_size is a multiple of frame_size
+		// SAFETY: frame is always smaller than tx_frames
+		unsafe { self.map.add(self.rx_size + frame * self.frame_size) as *mut Tpacket3Hdr }
+	}
+}
+
+impl Drop for RingSocket {
+	fn drop(&mut self) {
+		if !self.map.is_null() {
+			// SAFETY: map was created with this size in new
+			unsafe { libc::munmap(self.map as _, self.rx_size + self.tx_size) };
+		}
+		// SAFETY: fd is owned by self
+		unsafe { libc::close(self.fd) };
+	}
+}
+
+impl AsRawFd for RingSocket {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct RingStream(Arc<Async<RingSocket>>);
+
+impl RingStream {
+	pub fn new(name: &str, config: &RingConfig) -> Result<Self> {
+		let socket = RingSocket::new(name, config)?;
+		Ok(Self(Arc::new(Async::new(socket)?)))
+	}
+
+	pub fn get_ref(&self) -> &RingSocket {
+		self.0.get_ref()
+	}
+}
+
+impl Read for RingStream {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut TaskContext<'_>,
+		buf: &mut [u8],
+	) -> Poll<IoResult<usize>> {
+		loop {
+			match self.0.get_ref().recv(buf) {
+				Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+				res => return Poll::Ready(res),
+			}
+			match self.0.poll_readable(cx) {
+				Poll::Ready(Ok(())) => (),
+				Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+				Poll::Pending => return Poll::Pending,
+			}
+		}
+	}
+}
+
+impl Write for RingStream {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut TaskContext<'_>,
+		buf: &[u8],
+	) -> Poll<IoResult<usize>> {
+		loop {
+			match self.0.get_ref().send(buf) {
+				Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+				res => return Poll::Ready(res),
+			}
+			match self.0.poll_writable(cx) {
+				Poll::Ready(Ok(())) => (),
+				Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+				Poll::Pending => return Poll::Pending,
+			}
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<IoResult<()>> {
+		Poll::Ready(self.0.get_ref().flush())
+	}
+
+	fn poll_close(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<IoResult<()>> {
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl AsRawFd for RingStream {
+	fn as_raw_fd(&self) -> RawFd {
+		self.0.as_raw_fd()
+	}
+}
+
+fn setsockopt<T>(fd: RawFd, opt: libc::c_int, value: &T) -> IoResult<()> {
+	// SAFETY: call to c function, value is valid for size_of::<T>() bytes
+	match unsafe {
+		libc::setsockopt(
+			fd,
+			libc::SOL_PACKET,
+			opt,
+			value as *const T as _,
+			size_of::<T>() as _,
+		)
+	} {
+		-1 => Err(IoError::last_os_error()),
+		_ => Ok(()),
+	}
+}
+
+// libc helpers not defined in libc
+const PACKET_RX_RING: libc::c_int = 5;
+const PACKET_VERSION: libc::c_int = 10;
+const PACKET_TX_RING: libc::c_int = 13;
+const TPACKET_V3: libc::c_int = 2;
+const PACKET_OUTGOING: u8 = 4;
+
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+const TP_STATUS_AVAILABLE: u32 = 0;
+const TP_STATUS_SEND_REQUEST: u32 = 1;
+const TP_STATUS_WRONG_FORMAT: u32 = 4;
+const TP_STATUS_VLAN_VALID: u32 = 1 << 4;
+const TP_STATUS_VLAN_TPID_VALID: u32 = 1 << 6;
+
+/// TPACKET_ALIGN(sizeof(struct tpacket3_hdr)) + sizeof(struct sockaddr_ll)
+const TPACKET3_HDRLEN: usize = 48 + size_of::<libc::sockaddr_ll>();
+/// Offset of the frame data in a tx frame
+const TX_DATA_OFFSET: usize = TPACKET3_HDRLEN - size_of::<libc::sockaddr_ll>();
+
+#[repr(C)]
+struct TpacketReq3 {
+	tp_block_size: u32,
+	tp_block_nr: u32,
+	tp_frame_size: u32,
+	tp_frame_nr: u32,
+	tp_retire_blk_tov: u32,
+	tp_sizeof_priv: u32,
+	tp_feature_req_word: u32,
+}
+
+#[repr(C)]
+struct Tpacket3Hdr {
+	tp_next_offset: u32,
+	tp_sec: u32,
+	tp_nsec: u32,
+	tp_snaplen: u32,
+	tp_len: u32,
+	tp_status: u32,
+	tp_mac: u16,
+	tp_net: u16,
+	hv1_rxhash: u32,
+	hv1_vlan_tci: u32,
+	hv1_vlan_tpid: u16,
+	hv1_padding: u16,
+	tp_padding: [u8; 8],
+}
+
+/// struct tpacket_block_desc with the tpacket_hdr_v1 header
+#[repr(C)]
+struct TpacketBlockDesc {
+	version: u32,
+	offset_to_priv: u32,
+	block_status: u32,
+	num_pkts: u32,
+	offset_to_first_pkt: u32,
+	blk_len: u32,
+	seq_num: u64,
+	ts_first_pkt: [u32; 2],
+	ts_last_pkt: [u32; 2],
+}
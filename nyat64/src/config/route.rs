@@ -0,0 +1,104 @@
+use std::net::{IpAddr, Ipv6Addr};
+
+use anyhow::{Context, Result};
+use log::*;
+use serde::Deserialize;
+use tun::netlink::{LinkConfig, Netlink, Route};
+
+use crate::config::MapConfig;
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RoutesConfig {
+	/// Routing table for the routes, the main table if unset
+	pub table: Option<u32>,
+	pub metric: Option<u32>,
+}
+
+/// Routes to the `ipv6_local` addresses of the mappings over the tun, so the kernel hands
+/// packets for them to nyat64
+pub struct RouteManager {
+	name: String,
+	index: u32,
+	config: RoutesConfig,
+	/// Routes added by us, ones that already existed are left to their owner
+	installed: Vec<Route>,
+}
+
+impl RouteManager {
+	pub fn new(name: &str, config: &RoutesConfig) -> Result<Self> {
+		Ok(Self {
+			name: name.to_owned(),
+			index: Netlink::link_index(name)?,
+			config: config.clone(),
+			installed: Vec::new(),
+		})
+	}
+
+	/// Install the routes for `mappings` and remove the ones no longer needed
+	pub fn sync(&mut self, mappings: &[MapConfig]) -> Result<()> {
+		let mut wanted: Vec<Route> = Vec::with_capacity(mappings.len());
+		for route in mappings.iter().map(|m| self.route(m)) {
+			if !wanted.contains(&route) {
+				wanted.push(route);
+			}
+		}
+
+		let mut netlink = Netlink::new()?;
+
+		let config = LinkConfig {
+			routes: wanted
+				.iter()
+				.filter(|r| !self.installed.contains(r))
+				.copied()
+				.collect(),
+			..Default::default()
+		};
+		trace!("adding {} routes on {}", config.routes.len(), &self.name);
+		let created = netlink
+			.configure(self.index, &config)
+			.with_context(|| format!("Install routes on {}", &self.name))?;
+		for route in config.routes.iter().filter(|r| !created.routes.contains(r)) {
+			debug!("route {}/{} already exists", route.dst, route.prefix_len);
+		}
+
+		for route in self.installed.iter().filter(|r| !wanted.contains(r)) {
+			trace!("removing route {}/{}", route.dst, route.prefix_len);
+			if let Err(e) = netlink.del_route(self.index, route) {
+				warn!(
+					"failed to remove route {}/{}: {:?}",
+					route.dst, route.prefix_len, e
+				);
+			}
+		}
+
+		self.installed.retain(|r| wanted.contains(r));
+		self.installed.extend(created.routes);
+		debug!(
+			"{} routes installed on {}",
+			self.installed.len(),
+			&self.name
+		);
+
+		Ok(())
+	}
+
+	/// Remove all routes added by us, used on shutdown
+	pub fn remove_all(&mut self) -> Result<()> {
+		self.sync(&[])
+	}
+
+	fn route(&self, mapping: &MapConfig) -> Route {
+		let prefix_len = mapping.ipv6_prefix.unwrap_or(128).min(128);
+		let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+		let dst = Ipv6Addr::from(u128::from(mapping.ipv6_local) & mask);
+
+		Route {
+			dst: IpAddr::V6(dst),
+			prefix_len,
+			gateway: None,
+			table: self.config.table,
+			metric: self.config.metric,
+		}
+	}
+}
@@ -1,4 +1,5 @@
-use afpacket::r#async::RawPacketStream;
+use std::net::Ipv4Addr;
+
 use anyhow::{Context, Result};
 use async_std::io::prelude::*;
 use log::*;
@@ -13,42 +14,77 @@ use pnet::util::MacAddr;
 use tun::AsyncTunSocket;
 
 use crate::config::arp::ArpCache;
+use crate::config::tap::{Ingress, TapLink};
+use crate::config::vlan::VlanAddr;
 use crate::config::MapResult;
 
 pub async fn tun_to_dst(
-	mut tun: AsyncTunSocket,
-	iface_dst_write: RawPacketStream,
-	if_dst_mac: MacAddr,
+	tun: AsyncTunSocket,
 	apr_cache: ArpCache,
+	link: Option<TapLink>,
 ) -> Result<()> {
 	debug!("starting loop tun");
 
+	let mut reply = tun.clone();
+
+	// big enough for gso super packets, they are split before parsing
+	let mut gso_buf = vec![0u8; 1 << 16];
+
 	loop {
-		let mut buf = [0u8; 1500];
-		let size = tun
-			.read(&mut buf)
+		let (pi, hdr, size) = tun
+			.recv_headers(&mut gso_buf)
 			.await
 			.context("Failed to read tun stream")?;
 
 		trace!("got packet: tun");
 
-		let iface_dst_write = iface_dst_write.clone();
-		let arp_cache = apr_cache.clone();
-		async_std::task::spawn(async move {
-			if let Err(e) = parse(buf, size, iface_dst_write, if_dst_mac, arp_cache).await {
-				info!("failed to parse tun packet: {}", e);
+		if pi.is_truncated() {
+			debug!("dropping truncated tun packet");
+			continue;
+		}
+
+		let (start, size) = match &link {
+			None if !pi.is_ipv6() => {
+				debug!("Not an ipv6 packet: ethertype {:#06x}", pi.proto);
+				continue;
+			}
+			None => (0, size),
+			Some(link) => match link.ingress(&gso_buf[..size]) {
+				Ingress::Packet(packet) => (size - packet.len(), packet.len()),
+				Ingress::Reply(frame) => {
+					reply
+						.write_all(&frame)
+						.await
+						.context("Failed to write tap reply")?;
+					continue;
+				}
+				Ingress::Drop => continue,
+			},
+		};
+
+		let res = hdr.segment(&mut gso_buf[start..start + size], |packet| {
+			let mut buf = [0u8; 1500];
+			let size = packet.len();
+			if size > buf.len() {
+				debug!("dropping oversized tun packet: {} bytes", size);
+				return;
 			}
+			buf[..size].copy_from_slice(packet);
+
+			let arp_cache = apr_cache.clone();
+			async_std::task::spawn(async move {
+				if let Err(e) = parse(buf, size, arp_cache).await {
+					info!("failed to parse tun packet: {}", e);
+				}
+			});
 		});
+		if let Err(e) = res {
+			debug!("failed to segment tun packet: {}", e);
+		}
 	}
 }
 
-async fn parse(
-	buf: [u8; 1500],
-	size: usize,
-	mut iface_dst_write: RawPacketStream,
-	if_dst_mac: MacAddr,
-	arp_cache: ArpCache,
-) -> Result<()> {
+async fn parse(buf: [u8; 1500], size: usize, arp_cache: ArpCache) -> Result<()> {
 	#[cfg(feature = "debug")]
 	debug!("tun:\n{}", &(buf[..size]).to_hex(24));
 
@@ -80,38 +116,36 @@ async fn parse(
 	trace!("found mapping: {:?}", map);
 
 	let dst_ipv4_arp = if let Some(gw) = map.gw { gw } else { map.dst };
-	let mac = arp_cache
-		.request(&mut iface_dst_write, map.src, dst_ipv4_arp, if_dst_mac)
-		.await?;
-	if mac.is_none() {
-		trace!("Address not found on the dst iface");
-		return Ok(());
-	}
-	let mac = mac.unwrap();
+	let dst_ipv4_arp = VlanAddr::new(map.vlan, dst_ipv4_arp);
 
-	match ipv6.get_next_header() {
+	let frame = match ipv6.get_next_header() {
 		IpNextHeaderProtocols::Udp => {
-			parse_udp(buf, payload_start, map, iface_dst_write, mac, if_dst_mac).await
+			parse_udp(buf, payload_start, map.src, map.dst, arp_cache.mac())?
 		}
 		IpNextHeaderProtocols::Tcp => {
 			debug!("implement TCP");
-			Ok(())
+			return Ok(());
 		}
 		_ => {
 			debug!("Protocol not yet supported: {}", ipv6.get_next_header());
-			Ok(())
+			return Ok(());
 		}
-	}
+	};
+
+	// the destination is filled in by the arp cache
+	let frame = map.vlan.tag(frame);
+	arp_cache
+		.send(map.src, dst_ipv4_arp, frame, &buf[..size])
+		.await
 }
 
-async fn parse_udp(
+fn parse_udp(
 	mut buf: [u8; 1500],
 	udp_start: usize,
-	map: MapResult,
-	mut iface_dst_write: RawPacketStream,
-	dst_mac: MacAddr,
+	src: Ipv4Addr,
+	dst: Ipv4Addr,
 	src_mac: MacAddr,
-) -> Result<()> {
+) -> Result<Vec<u8>> {
 	use pnet::packet::udp::{MutableUdpPacket, UdpPacket};
 
 	let udp_repr = UdpPacket::new(&buf[udp_start..])
@@ -120,7 +154,7 @@ async fn parse_udp(
 
 	let mut ethernet =
 		MutableEthernetPacket::new(&mut buf).context("Failed to allocate ethernet packet")?;
-	ethernet.set_destination(dst_mac);
+	ethernet.set_destination(MacAddr::zero());
 	ethernet.set_source(src_mac);
 	ethernet.set_ethertype(EtherTypes::Ipv4);
 
@@ -139,8 +173,8 @@ async fn parse_udp(
 	ipv4.set_fragment_offset(0);
 	ipv4.set_ttl(64);
 	ipv4.set_next_level_protocol(IpNextHeaderProtocols::Udp);
-	ipv4.set_source(map.src);
-	ipv4.set_destination(map.dst);
+	ipv4.set_source(src);
+	ipv4.set_destination(dst);
 
 	ipv4.set_checksum(pnet::packet::ipv4::checksum(&ipv4.to_immutable()));
 
@@ -161,13 +195,9 @@ async fn parse_udp(
 	udp_buf.copy_from_slice(&udp_repr.payload[..udp_repr.length as usize - 8]);
 
 	//let length = length + udp.packet_size();
-	let checksum_udp = pnet::packet::udp::ipv4_checksum(&udp.to_immutable(), &map.src, &map.dst);
+	let checksum_udp = pnet::packet::udp::ipv4_checksum(&udp.to_immutable(), &src, &dst);
 	udp.set_checksum(checksum_udp);
 
 	trace!("writing: {:?}", ipv4);
-	iface_dst_write
-		.write_all(&ethernet.packet()[..length])
-		.await?;
-
-	Ok(())
+	Ok(ethernet.packet()[..length].to_vec())
 }
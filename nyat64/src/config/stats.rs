@@ -0,0 +1,204 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use log::*;
+use tun::netlink::LinkInfo;
+use tun::AsyncTunSocket;
+
+/// Global counters, read by the periodic stats log
+pub static STATS: Stats = Stats::new();
+
+#[derive(Debug)]
+pub struct Stats {
+	/// Frames per egress batch on the ipv4 interface
+	pub ipv4_batches: Histogram,
+	/// Packets per write on the tun, more than one are merged into a udp super packet
+	pub tun_batches: Histogram,
+	/// Frames dropped while waiting for arp resolution
+	pub arp_queue_drops: Counter,
+	/// Resolutions without a reply
+	pub arp_failed: Counter,
+	/// Malformed arp packets or bogus senders
+	pub arp_invalid: Counter,
+	/// Arp replies not addressed to our mac and addresses
+	pub arp_not_for_us: Counter,
+	/// Arp replies without an outstanding request
+	pub arp_unsolicited: Counter,
+	/// Served addresses given up because another host uses them
+	pub arp_conflicts: Counter,
+	/// Proxy arp requests not answered because of the rate limit
+	pub arp_proxy_limited: Counter,
+	/// Frames dropped because their source is probed for or used by another host
+	pub arp_unbound_drops: Counter,
+	/// ICMPv6 Address Unreachable sent for dropped frames
+	pub icmp_unreachable: Counter,
+	/// Received frames dropped because the vlan tag taken off by the kernel did not fit back in
+	pub vlan_tag_drops: Counter,
+}
+
+impl Stats {
+	const fn new() -> Self {
+		Self {
+			ipv4_batches: Histogram::new(),
+			tun_batches: Histogram::new(),
+			arp_queue_drops: Counter::new(),
+			arp_failed: Counter::new(),
+			arp_invalid: Counter::new(),
+			arp_not_for_us: Counter::new(),
+			arp_unsolicited: Counter::new(),
+			arp_conflicts: Counter::new(),
+			arp_proxy_limited: Counter::new(),
+			arp_unbound_drops: Counter::new(),
+			icmp_unreachable: Counter::new(),
+			vlan_tag_drops: Counter::new(),
+		}
+	}
+}
+
+impl Display for Stats {
+	fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+		writeln!(f, "ipv4 batches: {}", self.ipv4_batches)?;
+		writeln!(f, "tun batches: {}", self.tun_batches)?;
+		writeln!(f, "arp queue drops: {}", self.arp_queue_drops)?;
+		writeln!(f, "arp failed: {}", self.arp_failed)?;
+		writeln!(f, "arp invalid: {}", self.arp_invalid)?;
+		writeln!(f, "arp not for us: {}", self.arp_not_for_us)?;
+		writeln!(f, "arp unsolicited: {}", self.arp_unsolicited)?;
+		writeln!(f, "arp conflicts: {}", self.arp_conflicts)?;
+		writeln!(f, "arp proxy limited: {}", self.arp_proxy_limited)?;
+		writeln!(f, "arp unbound drops: {}", self.arp_unbound_drops)?;
+		writeln!(f, "icmp unreachable: {}", self.icmp_unreachable)?;
+		write!(f, "vlan tag drops: {}", self.vlan_tag_drops)
+	}
+}
+
+pub async fn log_periodic(interval: Duration, tun: AsyncTunSocket) {
+	loop {
+		async_std::task::sleep(interval).await;
+		info!("stats:\n{}", STATS);
+		match tun.link() {
+			Ok(link) => info!("tun: {}", TunHealth(link)),
+			Err(e) => warn!("failed to query tun state: {:?}", e),
+		}
+	}
+}
+
+/// Link state and kernel counters of the tun
+struct TunHealth(LinkInfo);
+
+impl Display for TunHealth {
+	fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+		let link = &self.0;
+		let stats = &link.stats;
+		write!(
+			f,
+			"up {}, running {}, carrier {}, ",
+			link.is_up(),
+			link.is_running(),
+			link.has_carrier()
+		)?;
+		write!(
+			f,
+			"rx {} packets {} bytes {} dropped, tx {} packets {} bytes {} dropped",
+			stats.rx_packets,
+			stats.rx_bytes,
+			stats.rx_dropped,
+			stats.tx_packets,
+			stats.tx_bytes,
+			stats.tx_dropped
+		)
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+	pub const fn new() -> Self {
+		Self(AtomicU64::new(0))
+	}
+
+	#[inline(always)]
+	pub fn inc(&self) {
+		self.add(1);
+	}
+
+	#[inline(always)]
+	pub fn add(&self, n: u64) {
+		self.0.fetch_add(n, Ordering::Relaxed);
+	}
+
+	pub fn get(&self) -> u64 {
+		self.0.load(Ordering::Relaxed)
+	}
+}
+
+impl Display for Counter {
+	fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+		write!(f, "{}", self.get())
+	}
+}
+
+const BUCKETS: usize = 8;
+
+/// Power of two histogram, the last bucket collects everything from 128 up
+#[derive(Debug)]
+pub struct Histogram {
+	buckets: [Counter; BUCKETS],
+	count: Counter,
+	sum: Counter,
+	max: AtomicU64,
+}
+
+impl Histogram {
+	pub const fn new() -> Self {
+		#[allow(clippy::declare_interior_mutable_const)]
+		const ZERO: Counter = Counter::new();
+		Self {
+			buckets: [ZERO; BUCKETS],
+			count: Counter::new(),
+			sum: Counter::new(),
+			max: AtomicU64::new(0),
+		}
+	}
+
+	pub fn record(&self, value: u64) {
+		let bucket = (64 - value.leading_zeros() as usize).saturating_sub(1);
+		self.buckets[bucket.min(BUCKETS - 1)].inc();
+		self.count.inc();
+		self.sum.add(value);
+		self.max.fetch_max(value, Ordering::Relaxed);
+	}
+}
+
+impl Default for Histogram {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Display for Histogram {
+	fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+		let count = self.count.get();
+		let avg = if count == 0 {
+			0.0
+		} else {
+			self.sum.get() as f64 / count as f64
+		};
+		write!(
+			f,
+			"count {}, avg {:.1}, max {} [",
+			count,
+			avg,
+			self.max.load(Ordering::Relaxed)
+		)?;
+		for (i, bucket) in self.buckets.iter().enumerate() {
+			if i != 0 {
+				write!(f, " ")?;
+			}
+			write!(f, "{}+: {}", 1 << i, bucket)?;
+		}
+		write!(f, "]")
+	}
+}
@@ -0,0 +1,168 @@
+use std::io::{ErrorKind, Result as IoResult};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use afpacket::r#async::RawPacketStream;
+use anyhow::Result;
+use async_std::io::prelude::*;
+use async_std::io::{Read, Write};
+use log::*;
+use nix::libc::sock_filter;
+use tun::AsyncTunSocket;
+
+use crate::config::ring::RingStream;
+use crate::config::xdp::XskStream;
+use crate::config::{filter, packet};
+
+/// Socket on the ipv4 interface, used by the src and dst loops
+#[derive(Clone)]
+pub enum Ipv4Stream {
+	/// AF_PACKET socket, one syscall per frame
+	Packet(RawPacketStream),
+	/// AF_PACKET socket with TPACKET_V3 rings
+	Ring(RingStream),
+	/// AF_XDP socket
+	Xdp(XskStream),
+	/// Tap device
+	Tap(AsyncTunSocket),
+}
+
+impl Ipv4Stream {
+	/// Only receive frames accepted by `rules`, packet sockets get the socket filter `prog` and
+	/// the xdp program is told which frames to redirect
+	pub fn set_filter(&self, prog: &[sock_filter], rules: &[filter::Rule]) -> Result<()> {
+		match self {
+			Ipv4Stream::Packet(stream) => filter::attach(stream, prog),
+			Ipv4Stream::Ring(stream) => filter::attach(stream, prog),
+			Ipv4Stream::Xdp(stream) => stream.get_ref().set_rules(rules),
+			Ipv4Stream::Tap(_) => {
+				trace!("not attaching socket filter to tap device");
+				Ok(())
+			}
+		}
+	}
+
+	/// Emit all frames with as few syscalls as the backend allows
+	pub async fn write_batch(&mut self, frames: &[Vec<u8>]) -> IoResult<()> {
+		let sent = match self {
+			Ipv4Stream::Packet(stream) => match packet::send_frames(stream, frames) {
+				Err(e) if e.kind() == ErrorKind::WouldBlock => 0,
+				res => res?,
+			},
+			Ipv4Stream::Ring(stream) => {
+				let ring = stream.get_ref();
+				let queued = queue_frames(frames, |frame| ring.queue(frame))?;
+				ring.flush()?;
+				queued
+			}
+			Ipv4Stream::Xdp(stream) => {
+				let xsk = stream.get_ref();
+				let queued = queue_frames(frames, |frame| xsk.queue(frame))?;
+				xsk.flush()?;
+				queued
+			}
+			// a tap write always carries exactly one frame
+			Ipv4Stream::Tap(_) => 0,
+		};
+
+		// the socket is full, wait for it with the remaining frames
+		for frame in &frames[sent..] {
+			self.write_all(frame).await?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Queue frames until the ring is full, returns the number of queued frames
+fn queue_frames(frames: &[Vec<u8>], queue: impl Fn(&[u8]) -> IoResult<()>) -> IoResult<usize> {
+	for (i, frame) in frames.iter().enumerate() {
+		match queue(frame) {
+			Ok(()) => (),
+			Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(i),
+			Err(e) => return Err(e),
+		}
+	}
+
+	Ok(frames.len())
+}
+
+impl Read for Ipv4Stream {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut [u8],
+	) -> Poll<IoResult<usize>> {
+		match self.get_mut() {
+			Ipv4Stream::Packet(stream) => Pin::new(stream).poll_read(cx, buf),
+			Ipv4Stream::Ring(stream) => Pin::new(stream).poll_read(cx, buf),
+			Ipv4Stream::Xdp(stream) => Pin::new(stream).poll_read(cx, buf),
+			Ipv4Stream::Tap(stream) => Pin::new(stream).poll_read(cx, buf),
+		}
+	}
+}
+
+impl Write for Ipv4Stream {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+		match self.get_mut() {
+			Ipv4Stream::Packet(stream) => Pin::new(stream).poll_write(cx, buf),
+			Ipv4Stream::Ring(stream) => Pin::new(stream).poll_write(cx, buf),
+			Ipv4Stream::Xdp(stream) => Pin::new(stream).poll_write(cx, buf),
+			Ipv4Stream::Tap(stream) => Pin::new(stream).poll_write(cx, buf),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+		match self.get_mut() {
+			Ipv4Stream::Packet(stream) => Pin::new(stream).poll_flush(cx),
+			Ipv4Stream::Ring(stream) => Pin::new(stream).poll_flush(cx),
+			Ipv4Stream::Xdp(stream) => Pin::new(stream).poll_flush(cx),
+			Ipv4Stream::Tap(stream) => Pin::new(stream).poll_flush(cx),
+		}
+	}
+
+	fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+		match self.get_mut() {
+			Ipv4Stream::Packet(stream) => Pin::new(stream).poll_close(cx),
+			Ipv4Stream::Ring(stream) => Pin::new(stream).poll_close(cx),
+			Ipv4Stream::Xdp(stream) => Pin::new(stream).poll_close(cx),
+			Ipv4Stream::Tap(stream) => Pin::new(stream).poll_close(cx),
+		}
+	}
+}
+
+impl AsRawFd for Ipv4Stream {
+	fn as_raw_fd(&self) -> RawFd {
+		match self {
+			Ipv4Stream::Packet(stream) => stream.as_raw_fd(),
+			Ipv4Stream::Ring(stream) => stream.as_raw_fd(),
+			Ipv4Stream::Xdp(stream) => stream.as_raw_fd(),
+			Ipv4Stream::Tap(stream) => stream.as_raw_fd(),
+		}
+	}
+}
+
+impl From<RawPacketStream> for Ipv4Stream {
+	fn from(stream: RawPacketStream) -> Self {
+		Ipv4Stream::Packet(stream)
+	}
+}
+
+impl From<RingStream> for Ipv4Stream {
+	fn from(stream: RingStream) -> Self {
+		Ipv4Stream::Ring(stream)
+	}
+}
+
+impl From<XskStream> for Ipv4Stream {
+	fn from(stream: XskStream) -> Self {
+		Ipv4Stream::Xdp(stream)
+	}
+}
+
+impl From<AsyncTunSocket> for Ipv4Stream {
+	fn from(stream: AsyncTunSocket) -> Self {
+		Ipv4Stream::Tap(stream)
+	}
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::sync::{Arc, Mutex};
+
+use log::*;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+
+use crate::config::icmp;
+use crate::config::MapResult;
+
+/// Result of a frame read from the ipv6 tap
+pub enum Ingress<'a> {
+	/// ipv6 packet without the ethernet header
+	Packet(&'a [u8]),
+	/// Frame to send back on the tap, e.g. a neighbor advertisement
+	Reply(Vec<u8>),
+	/// Nothing to do for us
+	Drop,
+}
+
+/// Ethernet framing and neighbor discovery for an ipv6 tap device
+#[derive(Debug, Clone)]
+pub struct TapLink {
+	mac: MacAddr,
+	neighbors: Arc<Mutex<HashMap<Ipv6Addr, MacAddr>>>,
+}
+
+impl TapLink {
+	pub fn new(mac: MacAddr) -> Self {
+		Self {
+			mac,
+			neighbors: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
+
+	/// Strip the ethernet header, learn the sender and answer neighbor solicitations
+	pub fn ingress<'a>(&self, frame: &'a [u8]) -> Ingress<'a> {
+		let ethernet = match EthernetPacket::new(frame) {
+			Some(ethernet) => ethernet,
+			None => return Ingress::Drop,
+		};
+		if ethernet.get_ethertype() != EtherTypes::Ipv6 {
+			trace!("tap: ignoring {}", ethernet.get_ethertype());
+			return Ingress::Drop;
+		}
+		let dst = ethernet.get_destination();
+		if dst != self.mac && !is_multicast(dst) {
+			return Ingress::Drop;
+		}
+
+		let packet = &frame[ETH_LEN..];
+		let ipv6 = match Ipv6Packet::new(packet) {
+			Some(ipv6) => ipv6,
+			None => return Ingress::Drop,
+		};
+		let src = ipv6.get_source();
+		if !src.is_unspecified() {
+			self.learn(src, ethernet.get_source());
+		}
+
+		if ipv6.get_next_header() == IpNextHeaderProtocols::Icmpv6 {
+			let icmp = ipv6.payload();
+			if icmp.len() >= 24 && icmp[0] == NDP_NEIGHBOR_SOLICIT {
+				let target = addr_at(icmp, 8);
+				if MapResult::find_v6_by_local(target).is_none() {
+					return Ingress::Drop;
+				}
+
+				trace!("tap: answering neighbor solicitation for {}", target);
+				return Ingress::Reply(self.advert(target, src, ethernet.get_source()));
+			}
+			if icmp.len() >= 24 && icmp[0] == NDP_NEIGHBOR_ADVERT {
+				// learned from the ethernet source already
+				return Ingress::Drop;
+			}
+		}
+
+		if is_multicast(dst) {
+			return Ingress::Drop;
+		}
+
+		Ingress::Packet(packet)
+	}
+
+	/// Prefix `packet` with an ethernet header for its destination.
+	///
+	/// Without a known neighbor the packet is dropped and a neighbor solicitation is returned
+	/// instead.
+	pub fn egress(&self, packet: &[u8]) -> Option<Vec<u8>> {
+		let ipv6 = Ipv6Packet::new(packet)?;
+		let dst = ipv6.get_destination();
+
+		let mac = self.neighbors.lock().unwrap().get(&dst).copied();
+		match mac {
+			Some(mac) => Some(self.frame(mac, packet)),
+			None => {
+				trace!("tap: no neighbor for {}, soliciting", dst);
+				Some(self.solicit(ipv6.get_source(), dst))
+			}
+		}
+	}
+
+	fn learn(&self, addr: Ipv6Addr, mac: MacAddr) {
+		let old = self.neighbors.lock().unwrap().insert(addr, mac);
+		if old != Some(mac) {
+			debug!("tap: neighbor {} is at {}", addr, mac);
+		}
+	}
+
+	fn frame(&self, dst: MacAddr, packet: &[u8]) -> Vec<u8> {
+		let mut buf = vec![0u8; ETH_LEN + packet.len()];
+		let mut ethernet = MutableEthernetPacket::new(&mut buf).unwrap();
+		ethernet.set_destination(dst);
+		ethernet.set_source(self.mac);
+		ethernet.set_ethertype(EtherTypes::Ipv6);
+		buf[ETH_LEN..].copy_from_slice(packet);
+		buf
+	}
+
+	fn advert(&self, target: Ipv6Addr, dst: Ipv6Addr, dst_mac: MacAddr) -> Vec<u8> {
+		// DAD probes come from the unspecified address and are answered to all nodes
+		let (dst, flags) = if dst.is_unspecified() {
+			(ALL_NODES, NA_OVERRIDE)
+		} else {
+			(dst, NA_SOLICITED | NA_OVERRIDE)
+		};
+
+		let mut icmp = [0u8; 32];
+		icmp[0] = NDP_NEIGHBOR_ADVERT;
+		icmp[4] = flags;
+		icmp[8..24].copy_from_slice(&target.octets());
+		icmp[24] = NDP_OPT_TARGET_LLADDR;
+		icmp[25] = 1;
+		icmp[26..32].copy_from_slice(&mac_octets(self.mac));
+
+		self.frame(dst_mac, &icmp::packet(target, dst, 255, &mut icmp))
+	}
+
+	fn solicit(&self, src: Ipv6Addr, target: Ipv6Addr) -> Vec<u8> {
+		let t = target.octets();
+		let dst = Ipv6Addr::new(
+			0xff02,
+			0,
+			0,
+			0,
+			0,
+			1,
+			0xff00 | t[13] as u16,
+			u16::from_be_bytes([t[14], t[15]]),
+		);
+		let dst_mac = MacAddr::new(0x33, 0x33, 0xff, t[13], t[14], t[15]);
+
+		let mut icmp = [0u8; 32];
+		icmp[0] = NDP_NEIGHBOR_SOLICIT;
+		icmp[8..24].copy_from_slice(&t);
+		icmp[24] = NDP_OPT_SOURCE_LLADDR;
+		icmp[25] = 1;
+		icmp[26..32].copy_from_slice(&mac_octets(self.mac));
+
+		self.frame(dst_mac, &icmp::packet(src, dst, 255, &mut icmp))
+	}
+}
+
+/// Address for our end of a tap device, the kernel side keeps the address of the device
+pub fn peer_mac(mac: MacAddr) -> MacAddr {
+	MacAddr::new(mac.0 | 0x02, mac.1, mac.2, mac.3, mac.4, mac.5 ^ 0x01)
+}
+
+fn addr_at(buf: &[u8], offset: usize) -> Ipv6Addr {
+	let mut octets = [0u8; 16];
+	octets.copy_from_slice(&buf[offset..offset + 16]);
+	octets.into()
+}
+
+fn is_multicast(mac: MacAddr) -> bool {
+	mac.0 & 0x01 != 0
+}
+
+fn mac_octets(mac: MacAddr) -> [u8; 6] {
+	[mac.0, mac.1, mac.2, mac.3, mac.4, mac.5]
+}
+
+const ETH_LEN: usize = 14;
+
+const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+const NDP_NEIGHBOR_SOLICIT: u8 = 135;
+const NDP_NEIGHBOR_ADVERT: u8 = 136;
+const NDP_OPT_SOURCE_LLADDR: u8 = 1;
+const NDP_OPT_TARGET_LLADDR: u8 = 2;
+
+const NA_SOLICITED: u8 = 0x40;
+const NA_OVERRIDE: u8 = 0x20;
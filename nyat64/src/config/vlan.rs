@@ -0,0 +1,281 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::net::Ipv4Addr;
+
+use anyhow::{bail, Result};
+use pnet::packet::ethernet::EtherType;
+use serde::{Deserialize, Serialize};
+
+/// 802.1Q tags of a frame, the outer tag is the 802.1ad S-tag of QinQ
+#[derive(
+	Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(default)]
+pub struct Vlan {
+	/// VLAN ID, the C-tag if there is an outer tag
+	#[serde(rename = "vlan", skip_serializing_if = "Option::is_none")]
+	pub inner: Option<u16>,
+	/// S-tag in front of `vlan`
+	#[serde(rename = "vlan_outer", skip_serializing_if = "Option::is_none")]
+	pub outer: Option<u16>,
+}
+
+impl Vlan {
+	pub fn is_tagged(&self) -> bool {
+		self.inner.is_some()
+	}
+
+	pub fn check(&self) -> Result<()> {
+		if self.outer.is_some() && self.inner.is_none() {
+			bail!("vlan_outer without vlan");
+		}
+		for id in self.outer.iter().chain(self.inner.iter()) {
+			if *id == 0 || *id >= 4095 {
+				bail!("Invalid vlan id {}", id);
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Insert our tags into an untagged ethernet frame
+	pub fn tag(&self, mut frame: Vec<u8>) -> Vec<u8> {
+		if !self.is_tagged() || frame.len() < ETH_ALEN * 2 {
+			return frame;
+		}
+
+		let mut tags = Vec::with_capacity(2 * TAG_LEN);
+		if let Some(outer) = self.outer {
+			tags.extend_from_slice(&ETH_P_8021AD.to_be_bytes());
+			tags.extend_from_slice(&outer.to_be_bytes());
+		}
+		if let Some(inner) = self.inner {
+			tags.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+			tags.extend_from_slice(&inner.to_be_bytes());
+		}
+		frame.splice(ETH_ALEN * 2..ETH_ALEN * 2, tags);
+		frame
+	}
+}
+
+impl Display for Vlan {
+	fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+		match (self.outer, self.inner) {
+			(Some(outer), Some(inner)) => write!(f, "vlan {}.{}", outer, inner),
+			(None, Some(inner)) => write!(f, "vlan {}", inner),
+			_ => write!(f, "untagged"),
+		}
+	}
+}
+
+/// Ipv4 address on a vlan, the same address may be used on several vlans
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VlanAddr {
+	pub vlan: Vlan,
+	pub addr: Ipv4Addr,
+}
+
+impl VlanAddr {
+	pub fn new(vlan: Vlan, addr: Ipv4Addr) -> Self {
+		Self { vlan, addr }
+	}
+}
+
+impl Display for VlanAddr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+		if self.vlan.is_tagged() {
+			write!(f, "{} on {}", self.addr, self.vlan)
+		} else {
+			write!(f, "{}", self.addr)
+		}
+	}
+}
+
+/// Read the tags of an ethernet frame, returns them with the ethertype behind them and the
+/// offset of the payload. Frames with more than two tags are not supported.
+pub fn parse(frame: &[u8]) -> Option<(Vlan, EtherType, usize)> {
+	let mut ids = [0u16; 2];
+	let mut tags = 0;
+	let mut offset = ETH_ALEN * 2;
+
+	for _ in 0..=ids.len() {
+		let ethertype = read_u16(frame, offset)?;
+		if ethertype != ETH_P_8021Q && ethertype != ETH_P_8021AD {
+			let vlan = match tags {
+				0 => Vlan::default(),
+				1 => Vlan {
+					inner: Some(ids[0]),
+					outer: None,
+				},
+				_ => Vlan {
+					inner: Some(ids[1]),
+					outer: Some(ids[0]),
+				},
+			};
+			return Some((vlan, EtherType(ethertype), offset + 2));
+		}
+
+		// priority tags without an id belong to the untagged network
+		let id = read_u16(frame, offset + 2)? & VLAN_VID_MASK;
+		if id != 0 {
+			if tags == ids.len() {
+				return None;
+			}
+			ids[tags] = id;
+			tags += 1;
+		}
+		offset += TAG_LEN;
+	}
+
+	None
+}
+
+/// Put a tag the kernel took off a received frame back in front of its ethertype. `len` is the
+/// length of the frame in `buf`, returns the new length or `None` if the tagged frame does not fit.
+pub fn insert_tag(buf: &mut [u8], len: usize, tpid: u16, tci: u16) -> Option<usize> {
+	let start = ETH_ALEN * 2;
+	if len < start || len + TAG_LEN > buf.len() {
+		return None;
+	}
+
+	buf.copy_within(start..len, start + TAG_LEN);
+	buf[start..start + 2].copy_from_slice(&tpid.to_be_bytes());
+	buf[start + 2..start + TAG_LEN].copy_from_slice(&tci.to_be_bytes());
+
+	Some(len + TAG_LEN)
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+	let bytes = buf.get(offset..offset + 2)?;
+	Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+const ETH_ALEN: usize = 6;
+const TAG_LEN: usize = 4;
+const VLAN_VID_MASK: u16 = 0x0fff;
+
+pub const ETH_P_8021Q: u16 = 0x8100;
+pub const ETH_P_8021AD: u16 = 0x88a8;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Frame with `tags` as (tpid, tci) in front of the ethertype
+	fn frame(tags: &[(u16, u16)], ethertype: u16) -> Vec<u8> {
+		let mut frame = vec![0xff; ETH_ALEN * 2];
+		for (tpid, tci) in tags {
+			frame.extend_from_slice(&tpid.to_be_bytes());
+			frame.extend_from_slice(&tci.to_be_bytes());
+		}
+		frame.extend_from_slice(&ethertype.to_be_bytes());
+		frame.extend_from_slice(&[0x45; 20]);
+		frame
+	}
+
+	fn vlan(outer: Option<u16>, inner: Option<u16>) -> Vlan {
+		Vlan { inner, outer }
+	}
+
+	#[test]
+	fn parse_untagged() {
+		let (tags, ethertype, offset) = parse(&frame(&[], 0x0800)).unwrap();
+		assert_eq!(tags, Vlan::default());
+		assert_eq!(ethertype, EtherType(0x0800));
+		assert_eq!(offset, 14);
+	}
+
+	#[test]
+	fn parse_tagged() {
+		// priority bits are not part of the id
+		let (tags, ethertype, offset) = parse(&frame(&[(ETH_P_8021Q, 0xe00a)], 0x0806)).unwrap();
+		assert_eq!(tags, vlan(None, Some(10)));
+		assert_eq!(ethertype, EtherType(0x0806));
+		assert_eq!(offset, 18);
+	}
+
+	#[test]
+	fn parse_qinq() {
+		let tags = [(ETH_P_8021AD, 100), (ETH_P_8021Q, 10)];
+		let (tags, _, offset) = parse(&frame(&tags, 0x0800)).unwrap();
+		assert_eq!(tags, vlan(Some(100), Some(10)));
+		assert_eq!(offset, 22);
+
+		// more than two tags
+		let tags = [(ETH_P_8021AD, 100), (ETH_P_8021Q, 10), (ETH_P_8021Q, 20)];
+		assert!(parse(&frame(&tags, 0x0800)).is_none());
+	}
+
+	#[test]
+	fn parse_priority_tags() {
+		let (tags, _, offset) = parse(&frame(&[(ETH_P_8021Q, 0xa000)], 0x0800)).unwrap();
+		assert_eq!(tags, Vlan::default());
+		assert_eq!(offset, 18);
+
+		let tags = [(ETH_P_8021Q, 0), (ETH_P_8021Q, 10)];
+		let (tags, _, offset) = parse(&frame(&tags, 0x0800)).unwrap();
+		assert_eq!(tags, vlan(None, Some(10)));
+		assert_eq!(offset, 22);
+
+		// a priority tag still counts towards the two tags
+		let tags = [(ETH_P_8021Q, 0), (ETH_P_8021AD, 100), (ETH_P_8021Q, 10)];
+		assert!(parse(&frame(&tags, 0x0800)).is_none());
+	}
+
+	#[test]
+	fn parse_truncated() {
+		assert!(parse(&[]).is_none());
+		assert!(parse(&frame(&[], 0x0800)[..13]).is_none());
+		let tagged = frame(&[(ETH_P_8021Q, 10)], 0x0800);
+		assert!(parse(&tagged[..15]).is_none());
+		assert!(parse(&tagged[..17]).is_none());
+		assert!(parse(&tagged[..18]).is_some());
+	}
+
+	#[test]
+	fn insert_tag_restores_frame() {
+		let untagged = frame(&[], 0x0800);
+		let tagged = frame(&[(ETH_P_8021Q, 0x200a)], 0x0800);
+		let mut buf = untagged.clone();
+		buf.resize(100, 0);
+
+		let len = insert_tag(&mut buf, untagged.len(), ETH_P_8021Q, 0x200a).unwrap();
+		assert_eq!(&buf[..len], &tagged[..]);
+		let (tags, _, _) = parse(&buf[..len]).unwrap();
+		assert_eq!(tags, vlan(None, Some(10)));
+
+		let len = insert_tag(&mut buf, len, ETH_P_8021AD, 100).unwrap();
+		let (tags, _, _) = parse(&buf[..len]).unwrap();
+		assert_eq!(tags, vlan(Some(100), Some(10)));
+	}
+
+	#[test]
+	fn insert_tag_full_buffer() {
+		let untagged = frame(&[], 0x0800);
+		let mut buf = untagged.clone();
+
+		// the tagged frame does not fit, nothing is moved
+		assert_eq!(insert_tag(&mut buf, untagged.len(), ETH_P_8021Q, 10), None);
+		assert_eq!(buf, untagged);
+
+		buf.resize(untagged.len() + 4, 0);
+		assert_eq!(
+			insert_tag(&mut buf, untagged.len(), ETH_P_8021Q, 10),
+			Some(buf.len())
+		);
+
+		// too short for a tag
+		let mut buf = [0u8; 14];
+		assert_eq!(insert_tag(&mut buf, 10, ETH_P_8021Q, 10), None);
+	}
+
+	#[test]
+	fn tag_round_trip() {
+		let untagged = frame(&[], 0x0800);
+		let tags = vlan(Some(100), Some(10));
+		let (parsed, ethertype, offset) = parse(&tags.tag(untagged.clone())).unwrap();
+		assert_eq!(parsed, tags);
+		assert_eq!(ethertype, EtherType(0x0800));
+		assert_eq!(offset, 22);
+		assert_eq!(Vlan::default().tag(untagged.clone()), untagged);
+	}
+}
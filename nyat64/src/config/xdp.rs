@@ -0,0 +1,1103 @@
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::future::Future;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use async_io::{Async, Timer};
+use async_std::io::{Read, Write};
+use log::*;
+use nix::libc;
+use serde::Deserialize;
+
+use crate::config::filter;
+
+/// UMEM and ring sizes for the AF_XDP socket
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct XdpConfig {
+	/// Interface queue the socket is bound to
+	pub queue_id: u32,
+	/// Size of a single UMEM frame, 2048 or 4096
+	pub frame_size: u32,
+	/// Number of UMEM frames, half of them is used for receiving
+	pub frame_count: u32,
+	/// Number of descriptors in each ring, has to be a power of two
+	pub ring_size: u32,
+	/// Attach the program in driver mode, instead of generic (skb) mode
+	pub native: bool,
+}
+
+impl Default for XdpConfig {
+	fn default() -> Self {
+		Self {
+			queue_id: 0,
+			frame_size: 2048,
+			frame_count: 4096,
+			ring_size: 2048,
+			native: false,
+		}
+	}
+}
+
+/// Single producer/single consumer ring shared with the kernel
+#[derive(Debug)]
+struct XskRing {
+	map: *mut u8,
+	map_len: usize,
+	producer: *const AtomicU32,
+	consumer: *const AtomicU32,
+	desc: *mut u8,
+	mask: u32,
+}
+
+impl XskRing {
+	fn map(
+		fd: RawFd,
+		offsets: &XdpRingOffset,
+		size: u32,
+		desc_size: usize,
+		pgoff: i64,
+	) -> Result<Self> {
+		let map_len = offsets.desc as usize + size as usize * desc_size;
+
+		// SAFETY: call to c function, the ring was set up with this size
+		let map = match unsafe {
+			libc::mmap(
+				ptr::null_mut(),
+				map_len,
+				libc::PROT_READ | libc::PROT_WRITE,
+				libc::MAP_SHARED | libc::MAP_POPULATE,
+				fd,
+				pgoff,
+			)
+		} {
+			libc::MAP_FAILED => return Err(IoError::last_os_error()).context("mmap xsk ring"),
+			map => map as *mut u8,
+		};
+
+		// SAFETY: offsets are reported by the kernel and inside of the mapping
+		unsafe {
+			Ok(Self {
+				map,
+				map_len,
+				producer: map.add(offsets.producer as usize) as _,
+				consumer: map.add(offsets.consumer as usize) as _,
+				desc: map.add(offsets.desc as usize),
+				mask: size - 1,
+			})
+		}
+	}
+
+	fn producer(&self) -> &AtomicU32 {
+		// SAFETY: pointer into the ring mapping, lives as long as self
+		unsafe { &*self.producer }
+	}
+
+	fn consumer(&self) -> &AtomicU32 {
+		// SAFETY: pointer into the ring mapping, lives as long as self
+		unsafe { &*self.consumer }
+	}
+
+	/// Entries ready for the consumer
+	fn available(&self) -> u32 {
+		let prod = self.producer().load(Ordering::Acquire);
+		prod.wrapping_sub(self.consumer().load(Ordering::Relaxed))
+	}
+
+	/// Free entries for the producer
+	fn free(&self) -> u32 {
+		let cons = self.consumer().load(Ordering::Acquire);
+		(self.mask + 1) - self.producer().load(Ordering::Relaxed).wrapping_sub(cons)
+	}
+
+	fn addr(&self, idx: u32) -> *mut u64 {
+		// SAFETY: idx is masked into the descriptor array
+		unsafe { (self.desc as *mut u64).add((idx & self.mask) as usize) }
+	}
+
+	fn xdp_desc(&self, idx: u32) -> *mut XdpDesc {
+		// SAFETY: idx is masked into the descriptor array
+		unsafe { (self.desc as *mut XdpDesc).add((idx & self.mask) as usize) }
+	}
+}
+
+impl Drop for XskRing {
+	fn drop(&mut self) {
+		// SAFETY: map was created with this size in map
+		unsafe { libc::munmap(self.map as _, self.map_len) };
+	}
+}
+
+#[derive(Debug)]
+struct XskState {
+	rx: XskRing,
+	tx: XskRing,
+	fill: XskRing,
+	completion: XskRing,
+	/// UMEM frames not owned by the kernel, used for transmitting
+	free: VecDeque<u64>,
+	/// received frames waiting to be handed back to the fill ring
+	refill: Vec<u64>,
+}
+
+/// AF_XDP socket bound to a single interface queue
+#[derive(Debug)]
+pub struct XskSocket {
+	fd: RawFd,
+	umem: *mut u8,
+	umem_len: usize,
+	frame_size: usize,
+	state: Mutex<XskState>,
+
+	prog_fd: RawFd,
+	map_fd: RawFd,
+	addr_map_fd: RawFd,
+	link_fd: RawFd,
+	/// Keys in the address map
+	addrs: Mutex<Vec<bpf::AddrKey>>,
+}
+
+// SAFETY: the umem and rings are only accessed while holding the state mutex
+unsafe impl Send for XskSocket {}
+unsafe impl Sync for XskSocket {}
+
+impl XskSocket {
+	pub fn new(name: &str, config: &XdpConfig) -> Result<Self> {
+		if !config.ring_size.is_power_of_two() || !config.frame_size.is_power_of_two() {
+			bail!("xdp ring and frame sizes have to be a power of two");
+		}
+
+		let iface_name = CString::new(name).context("Invalid interface name")?;
+		// SAFETY: call to c function, iface_name is a valid c string
+		let ifindex = match unsafe { libc::if_nametoindex(iface_name.as_ptr()) } {
+			0 => return Err(IoError::last_os_error()).context("Get interface index"),
+			index => index,
+		};
+
+		// SAFETY: call to c function, parameters are const
+		let fd = match unsafe { libc::socket(AF_XDP, libc::SOCK_RAW, 0) } {
+			-1 => return Err(IoError::last_os_error()).context("Open xdp socket"),
+			fd => fd,
+		};
+
+		let umem_len = config.frame_size as usize * config.frame_count as usize;
+		// SAFETY: call to c function, anonymous mapping
+		let umem = match unsafe {
+			libc::mmap(
+				ptr::null_mut(),
+				umem_len,
+				libc::PROT_READ | libc::PROT_WRITE,
+				libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+				-1,
+				0,
+			)
+		} {
+			libc::MAP_FAILED => {
+				// SAFETY: fd is owned by us
+				unsafe { libc::close(fd) };
+				return Err(IoError::last_os_error()).context("mmap umem");
+			}
+			map => map as *mut u8,
+		};
+
+		let state = match Self::setup(fd, umem, umem_len, ifindex, config) {
+			Ok(state) => state,
+			Err(e) => {
+				// SAFETY: both are owned by us and not used anymore
+				unsafe {
+					libc::munmap(umem as _, umem_len);
+					libc::close(fd);
+				}
+				return Err(e);
+			}
+		};
+
+		// build the struct before loading the program, so drop cleans up on errors
+		let mut socket = Self {
+			fd,
+			umem,
+			umem_len,
+			frame_size: config.frame_size as usize,
+			state: Mutex::new(state),
+			prog_fd: -1,
+			map_fd: -1,
+			addr_map_fd: -1,
+			link_fd: -1,
+			addrs: Mutex::new(Vec::new()),
+		};
+
+		socket.map_fd = bpf::create_xsk_map(config.queue_id + 1)?;
+		bpf::update_elem(socket.map_fd, &config.queue_id, &(fd as u32))
+			.context("Insert socket into xsk map")?;
+		// empty until the mappings are applied, nothing is redirected before
+		socket.addr_map_fd = bpf::create_addr_map(MAX_ADDRS)?;
+		socket.prog_fd = bpf::load_redirect_prog(socket.map_fd, socket.addr_map_fd)?;
+
+		let flags = if config.native {
+			XDP_FLAGS_DRV_MODE
+		} else {
+			XDP_FLAGS_SKB_MODE
+		};
+		socket.link_fd = bpf::link_xdp(socket.prog_fd, ifindex, flags)
+			.with_context(|| format!("Attach xdp program to {}", name))?;
+		debug!("attached xdp program to {} queue {}", name, config.queue_id);
+
+		Ok(socket)
+	}
+
+	fn setup(
+		fd: RawFd,
+		umem: *mut u8,
+		umem_len: usize,
+		ifindex: u32,
+		config: &XdpConfig,
+	) -> Result<XskState> {
+		let reg = XdpUmemReg {
+			addr: umem as u64,
+			len: umem_len as u64,
+			chunk_size: config.frame_size,
+			headroom: 0,
+			flags: 0,
+			tx_metadata_len: 0,
+		};
+		setsockopt(fd, XDP_UMEM_REG, &reg).context("Register umem")?;
+
+		let size = config.ring_size;
+		setsockopt(fd, XDP_UMEM_FILL_RING, &size).context("Set fill ring size")?;
+		setsockopt(fd, XDP_UMEM_COMPLETION_RING, &size).context("Set completion ring size")?;
+		setsockopt(fd, XDP_RX_RING, &size).context("Set rx ring size")?;
+		setsockopt(fd, XDP_TX_RING, &size).context("Set tx ring size")?;
+
+		// SAFETY: all zero is valid for plain integers
+		let mut offsets: XdpMmapOffsets = unsafe { std::mem::zeroed() };
+		let mut len = size_of::<XdpMmapOffsets>() as libc::socklen_t;
+		// SAFETY: call to c function, offsets is big enough
+		if unsafe {
+			libc::getsockopt(
+				fd,
+				SOL_XDP,
+				XDP_MMAP_OFFSETS,
+				&mut offsets as *mut _ as _,
+				&mut len,
+			)
+		} < 0
+		{
+			return Err(IoError::last_os_error()).context("Get xdp mmap offsets");
+		}
+
+		let desc = size_of::<XdpDesc>();
+		let mut state = XskState {
+			rx: XskRing::map(fd, &offsets.rx, size, desc, XDP_PGOFF_RX_RING)?,
+			tx: XskRing::map(fd, &offsets.tx, size, desc, XDP_PGOFF_TX_RING)?,
+			fill: XskRing::map(
+				fd,
+				&offsets.fr,
+				size,
+				size_of::<u64>(),
+				XDP_UMEM_PGOFF_FILL_RING,
+			)?,
+			completion: XskRing::map(
+				fd,
+				&offsets.cr,
+				size,
+				size_of::<u64>(),
+				XDP_UMEM_PGOFF_COMPLETION_RING,
+			)?,
+			free: VecDeque::new(),
+			refill: Vec::new(),
+		};
+
+		// first half of the umem is handed to the kernel for receiving, the rest is for us
+		let frames = (0..config.frame_count as u64).map(|f| f * config.frame_size as u64);
+		let rx_frames = (config.frame_count / 2).min(size);
+		state.refill.extend(frames.clone().take(rx_frames as usize));
+		state.free.extend(frames.skip(rx_frames as usize));
+		state.refill_fill();
+
+		let sxdp = SockaddrXdp {
+			sxdp_family: AF_XDP as _,
+			sxdp_flags: if config.native { 0 } else { XDP_COPY },
+			sxdp_ifindex: ifindex,
+			sxdp_queue_id: config.queue_id,
+			sxdp_shared_umem_fd: 0,
+		};
+		// SAFETY: call to c function, sxdp is a valid sockaddr_xdp
+		if unsafe { libc::bind(fd, &sxdp as *const _ as _, size_of::<SockaddrXdp>() as _) } < 0 {
+			return Err(IoError::last_os_error()).context("Bind xdp socket");
+		}
+
+		Ok(state)
+	}
+
+	/// Copy the next received frame into `buf`
+	pub fn recv(&self, buf: &mut [u8]) -> IoResult<usize> {
+		let mut state = self.state.lock().unwrap();
+
+		if state.rx.available() == 0 {
+			state.refill_fill();
+			return Err(ErrorKind::WouldBlock.into());
+		}
+
+		let cons = state.rx.consumer().load(Ordering::Relaxed);
+		// SAFETY: the descriptor is owned by us until the consumer is moved
+		let desc = unsafe { ptr::read(state.rx.xdp_desc(cons)) };
+		let len = (desc.len as usize).min(buf.len());
+		// SAFETY: the kernel only reports addresses inside of the umem
+		let frame = unsafe { std::slice::from_raw_parts(self.umem.add(desc.addr as usize), len) };
+		buf[..len].copy_from_slice(frame);
+		state
+			.rx
+			.consumer()
+			.store(cons.wrapping_add(1), Ordering::Release);
+
+		let base = desc.addr & !(self.frame_size as u64 - 1);
+		state.refill.push(base);
+		// hand back received frames in batches
+		if state.refill.len() >= 16 {
+			state.refill_fill();
+		}
+
+		Ok(len)
+	}
+
+	/// Queue `buf` in the tx ring, without notifying the kernel
+	pub fn queue(&self, buf: &[u8]) -> IoResult<()> {
+		if buf.len() > self.frame_size {
+			return Err(IoError::new(
+				ErrorKind::InvalidInput,
+				"frame too big for umem",
+			));
+		}
+
+		let mut state = self.state.lock().unwrap();
+		state.complete();
+
+		if state.tx.free() == 0 {
+			return Err(ErrorKind::WouldBlock.into());
+		}
+		let addr = match state.free.pop_front() {
+			Some(addr) => addr,
+			None => return Err(ErrorKind::WouldBlock.into()),
+		};
+
+		// SAFETY: addr is a free frame inside of the umem, buf fits into it
+		unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), self.umem.add(addr as usize), buf.len()) };
+
+		let prod = state.tx.producer().load(Ordering::Relaxed);
+		// SAFETY: there is a free descriptor, checked above
+		unsafe {
+			ptr::write(
+				state.tx.xdp_desc(prod),
+				XdpDesc {
+					addr,
+					len: buf.len() as _,
+					options: 0,
+				},
+			)
+		};
+		state
+			.tx
+			.producer()
+			.store(prod.wrapping_add(1), Ordering::Release);
+
+		Ok(())
+	}
+
+	/// Ask the kernel to send all queued frames
+	pub fn flush(&self) -> IoResult<()> {
+		// SAFETY: call to c function, an empty send kicks the tx ring
+		match unsafe { libc::sendto(self.fd, ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0) } {
+			-1 => match IoError::last_os_error() {
+				e if e.kind() == ErrorKind::WouldBlock => Ok(()),
+				// ENOBUFS: the kernel could not send everything, the rest is sent with the next kick
+				e if e.raw_os_error() == Some(libc::ENOBUFS) => Ok(()),
+				e => Err(e),
+			},
+			_ => Ok(()),
+		}
+	}
+
+	pub fn send(&self, buf: &[u8]) -> IoResult<usize> {
+		self.queue(buf)?;
+		self.flush()?;
+		Ok(buf.len())
+	}
+
+	/// Collect frames the kernel finished transmitting, returns whether a frame can be queued
+	pub fn can_queue(&self) -> bool {
+		let mut state = self.state.lock().unwrap();
+		state.complete();
+		!state.free.is_empty() && state.tx.free() > 0
+	}
+
+	/// Redirect ipv4 frames and ARP accepted by `rules` on their vlan into the socket, all other
+	/// frames are passed to the kernel
+	pub fn set_rules(&self, rules: &[filter::Rule]) -> Result<()> {
+		let entries: Vec<(bpf::AddrKey, u32)> = rules
+			.iter()
+			.map(|rule| {
+				(
+					bpf::AddrKey::new(rule.vlan, rule.addr, rule.prefix_len),
+					rule.accept as u32,
+				)
+			})
+			.collect();
+
+		let mut installed = self.addrs.lock().unwrap();
+		for key in installed
+			.iter()
+			.filter(|key| !entries.iter().any(|(k, _)| k == *key))
+		{
+			bpf::delete_elem(self.addr_map_fd, key).context("Remove address from xdp map")?;
+		}
+		installed.clear();
+		for (key, accept) in entries {
+			bpf::update_elem(self.addr_map_fd, &key, &accept)
+				.context("Insert address into xdp map")?;
+			installed.push(key);
+		}
+
+		Ok(())
+	}
+}
+
+impl XskState {
+	/// Give frames back to the kernel for receiving
+	fn refill_fill(&mut self) {
+		let count = self.fill.free().min(self.refill.len() as u32);
+		let prod = self.fill.producer().load(Ordering::Relaxed);
+		for i in 0..count {
+			let addr = self.refill.pop().unwrap();
+			// SAFETY: there are count free entries in the fill ring
+			unsafe { ptr::write(self.fill.addr(prod.wrapping_add(i)), addr) };
+		}
+		self.fill
+			.producer()
+			.store(prod.wrapping_add(count), Ordering::Release);
+	}
+
+	/// Collect frames the kernel finished transmitting
+	fn complete(&mut self) {
+		let count = self.completion.available();
+		let cons = self.completion.consumer().load(Ordering::Relaxed);
+		for i in 0..count {
+			// SAFETY: there are count used entries in the completion ring
+			let addr = unsafe { ptr::read(self.completion.addr(cons.wrapping_add(i))) };
+			self.free.push_back(addr);
+		}
+		self.completion
+			.consumer()
+			.store(cons.wrapping_add(count), Ordering::Release);
+	}
+}
+
+impl Drop for XskSocket {
+	fn drop(&mut self) {
+		// SAFETY: all fds are owned by self, closing the link detaches the program
+		unsafe {
+			for fd in [self.link_fd, self.prog_fd, self.map_fd, self.addr_map_fd] {
+				if fd >= 0 {
+					libc::close(fd);
+				}
+			}
+			libc::close(self.fd);
+		}
+
+		// SAFETY: umem was created with this size in new, the kernel holds its own reference
+		// until the socket is released
+		unsafe { libc::munmap(self.umem as _, self.umem_len) };
+	}
+}
+
+impl AsRawFd for XskSocket {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd
+	}
+}
+
+#[derive(Debug)]
+pub struct XskStream {
+	socket: Arc<Async<XskSocket>>,
+	/// Wakes a writer waiting for frames to come back through the completion ring
+	retry: Option<Timer>,
+}
+
+impl XskStream {
+	pub fn new(name: &str, config: &XdpConfig) -> Result<Self> {
+		let socket = XskSocket::new(name, config)?;
+		Ok(Self {
+			socket: Arc::new(Async::new(socket)?),
+			retry: None,
+		})
+	}
+
+	pub fn get_ref(&self) -> &XskSocket {
+		self.socket.get_ref()
+	}
+}
+
+impl Clone for XskStream {
+	fn clone(&self) -> Self {
+		Self {
+			socket: self.socket.clone(),
+			retry: None,
+		}
+	}
+}
+
+impl Read for XskStream {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut TaskContext<'_>,
+		buf: &mut [u8],
+	) -> Poll<IoResult<usize>> {
+		loop {
+			match self.socket.get_ref().recv(buf) {
+				Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+				res => return Poll::Ready(res),
+			}
+			match self.socket.poll_readable(cx) {
+				Poll::Ready(Ok(())) => (),
+				Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+				Poll::Pending => return Poll::Pending,
+			}
+		}
+	}
+}
+
+impl Write for XskStream {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut TaskContext<'_>,
+		buf: &[u8],
+	) -> Poll<IoResult<usize>> {
+		let this = self.get_mut();
+		let socket = this.socket.get_ref();
+		loop {
+			match socket.send(buf) {
+				Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+				res => {
+					this.retry = None;
+					return Poll::Ready(res);
+				}
+			}
+			// no free frame, kick the kernel so sent frames are returned
+			if let Err(e) = socket.flush() {
+				return Poll::Ready(Err(e));
+			}
+			if socket.can_queue() {
+				continue;
+			}
+
+			// POLLOUT only reports room in the tx ring, the completion ring has no wakeup
+			let retry = this.retry.get_or_insert_with(|| Timer::after(TX_RETRY));
+			match Pin::new(retry).poll(cx) {
+				Poll::Ready(_) => this.retry = None,
+				Poll::Pending => return Poll::Pending,
+			}
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<IoResult<()>> {
+		Poll::Ready(self.socket.get_ref().flush())
+	}
+
+	fn poll_close(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<IoResult<()>> {
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl AsRawFd for XskStream {
+	fn as_raw_fd(&self) -> RawFd {
+		self.socket.as_raw_fd()
+	}
+}
+
+fn setsockopt<T>(fd: RawFd, opt: libc::c_int, value: &T) -> IoResult<()> {
+	// SAFETY: call to c function, value is valid for size_of::<T>() bytes
+	match unsafe {
+		libc::setsockopt(
+			fd,
+			SOL_XDP,
+			opt,
+			value as *const T as _,
+			size_of::<T>() as _,
+		)
+	} {
+		-1 => Err(IoError::last_os_error()),
+		_ => Ok(()),
+	}
+}
+
+/// Whether the driver of `ifname` takes the vlan tags off received frames. The xdp program runs
+/// before they are put back, so it would see them untagged.
+pub fn rx_vlan_offload(ifname: &str) -> Result<bool> {
+	// SAFETY: call to c function without side effects
+	let fd = match unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) } {
+		-1 => return Err(IoError::last_os_error()).context("Open control socket"),
+		fd => fd,
+	};
+	let res = ethtool_features(fd, ifname, &["rx-vlan-hw-parse", "rx-vlan-stag-hw-parse"]);
+	// SAFETY: call to c function, fd is valid
+	unsafe { libc::close(fd) };
+
+	Ok(res?.into_iter().any(|active| active))
+}
+
+/// Active state of the named features of `ifname`, unknown features are inactive
+fn ethtool_features(fd: RawFd, ifname: &str, names: &[&str]) -> Result<Vec<bool>> {
+	// number of features
+	let mut info = [0u8; 20];
+	info[0..4].copy_from_slice(&ETHTOOL_GSSET_INFO.to_ne_bytes());
+	info[8..16].copy_from_slice(&(1u64 << ETH_SS_FEATURES).to_ne_bytes());
+	ethtool(fd, ifname, &mut info).context("ethtool get feature count")?;
+	let count = u32::from_ne_bytes([info[16], info[17], info[18], info[19]]) as usize;
+
+	let mut strings = vec![0u8; 12 + count * ETH_GSTRING_LEN];
+	strings[0..4].copy_from_slice(&ETHTOOL_GSTRINGS.to_ne_bytes());
+	strings[4..8].copy_from_slice(&ETH_SS_FEATURES.to_ne_bytes());
+	strings[8..12].copy_from_slice(&(count as u32).to_ne_bytes());
+	ethtool(fd, ifname, &mut strings).context("ethtool get feature names")?;
+
+	// available, requested, active and never changed bits of 32 features per block
+	let blocks = count.div_ceil(32);
+	let mut features = vec![0u8; 8 + blocks * 16];
+	features[0..4].copy_from_slice(&ETHTOOL_GFEATURES.to_ne_bytes());
+	features[4..8].copy_from_slice(&(blocks as u32).to_ne_bytes());
+	ethtool(fd, ifname, &mut features).context("ethtool get features")?;
+
+	let active = |index: usize| {
+		let offset = 8 + index / 32 * 16 + 8;
+		let bits = &features[offset..offset + 4];
+		u32::from_ne_bytes([bits[0], bits[1], bits[2], bits[3]]) & 1 << (index % 32) != 0
+	};
+	let states = names
+		.iter()
+		.map(|name| {
+			strings[12..]
+				.chunks(ETH_GSTRING_LEN)
+				.position(|string| string.split(|c| *c == 0).next() == Some(name.as_bytes()))
+				.is_some_and(active)
+		})
+		.collect();
+
+	Ok(states)
+}
+
+/// SIOCETHTOOL with the command at the start of `data`
+fn ethtool(fd: RawFd, ifname: &str, data: &mut [u8]) -> IoResult<()> {
+	let name = ifname.as_bytes();
+	if name.len() >= libc::IF_NAMESIZE {
+		return Err(IoError::from(ErrorKind::InvalidInput));
+	}
+	let mut ifr = EthtoolIfreq {
+		ifr_name: [0; libc::IF_NAMESIZE],
+		ifr_data: data.as_mut_ptr(),
+		_pad: [0; 16],
+	};
+	ifr.ifr_name[..name.len()].copy_from_slice(name);
+
+	// SAFETY: call to c function, ifr and the buffer it points to are valid
+	match unsafe { libc::ioctl(fd, SIOCETHTOOL as _, &mut ifr) } {
+		-1 => Err(IoError::last_os_error()),
+		_ => Ok(()),
+	}
+}
+
+/// Minimal bpf(2) wrapper to load and attach the redirect program
+mod bpf {
+	use std::io::Error as IoError;
+	use std::mem::size_of;
+	use std::net::Ipv4Addr;
+	use std::os::unix::io::RawFd;
+
+	use anyhow::{Context, Result};
+	use nix::libc;
+
+	use crate::config::vlan::Vlan;
+
+	const BPF_MAP_CREATE: libc::c_int = 0;
+	const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+	const BPF_MAP_DELETE_ELEM: libc::c_int = 3;
+	const BPF_PROG_LOAD: libc::c_int = 5;
+	const BPF_LINK_CREATE: libc::c_int = 28;
+
+	const BPF_MAP_TYPE_LPM_TRIE: u32 = 11;
+	const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+	const BPF_F_NO_PREALLOC: u32 = 1;
+	const BPF_PROG_TYPE_XDP: u32 = 6;
+	const BPF_XDP: u32 = 37;
+
+	const BPF_FUNC_MAP_LOOKUP_ELEM: i32 = 1;
+	const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+	const BPF_PSEUDO_MAP_FD: u8 = 1;
+	const XDP_PASS: i32 = 2;
+
+	#[repr(C)]
+	#[derive(Clone, Copy)]
+	struct Insn {
+		code: u8,
+		/// dst_reg in the lower, src_reg in the upper nibble
+		regs: u8,
+		off: i16,
+		imm: i32,
+	}
+
+	const fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+		Insn {
+			code,
+			regs: dst | (src << 4),
+			off,
+			imm,
+		}
+	}
+
+	/// Key of the address map, an LPM trie matched against the tags and destination of a frame.
+	/// The tags are always compared in full, `prefix_len` includes their 32 bits. The value is 1
+	/// for redirected and 0 for passed destinations.
+	#[repr(C)]
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct AddrKey {
+		prefix_len: u32,
+		outer: [u8; 2],
+		inner: [u8; 2],
+		addr: [u8; 4],
+	}
+
+	impl AddrKey {
+		pub fn new(vlan: Vlan, addr: Ipv4Addr, prefix_len: u8) -> Self {
+			Self {
+				prefix_len: 32 + prefix_len as u32,
+				outer: vlan.outer.unwrap_or(0).to_be_bytes(),
+				inner: vlan.inner.unwrap_or(0).to_be_bytes(),
+				addr: addr.octets(),
+			}
+		}
+	}
+
+	// instruction classes and modes used by the program
+	const LDX_W: u8 = 0x61;
+	const LDX_H: u8 = 0x69;
+	const ST_W: u8 = 0x62;
+	const STX_W: u8 = 0x63;
+	const STX_H: u8 = 0x6b;
+	const MOV64_X: u8 = 0xbf;
+	const MOV64_K: u8 = 0xb7;
+	const ADD64_K: u8 = 0x07;
+	const AND64_K: u8 = 0x57;
+	const JA: u8 = 0x05;
+	const JGT_X: u8 = 0x2d;
+	const JEQ_K: u8 = 0x15;
+	const JNE_K: u8 = 0x55;
+	const LD_IMM64: u8 = 0x18;
+	const CALL: u8 = 0x85;
+	const EXIT: u8 = 0x95;
+
+	/// Redirect ipv4 frames to an address redirected by the address map and ARP asking for one
+	/// into the socket of the receiving queue, pass all others. Up to two vlan tags are read like
+	/// [`vlan::parse`](crate::config::vlan::parse) does, priority tags without an id are skipped.
+	fn redirect_prog(map_fd: RawFd, addr_map_fd: RawFd) -> Vec<Insn> {
+		// ethertype and tag control information as read from the packet by a 16 bit load
+		let ipv4 = u16::from_ne_bytes(0x0800u16.to_be_bytes()) as i32;
+		let arp = u16::from_ne_bytes(0x0806u16.to_be_bytes()) as i32;
+		let dot1q = u16::from_ne_bytes(0x8100u16.to_be_bytes()) as i32;
+		let dot1ad = u16::from_ne_bytes(0x88a8u16.to_be_bytes()) as i32;
+		let vid_mask = u16::from_ne_bytes(0x0fffu16.to_be_bytes()) as i32;
+
+		let mut prog = vec![
+			// r6 = ctx, r2 = ctx->data, r3 = ctx->data_end, r8 = inner id, r9 = outer id
+			insn(MOV64_X, 6, 1, 0, 0),
+			insn(LDX_W, 2, 6, 0, 0),
+			insn(LDX_W, 3, 6, 4, 0),
+			insn(MOV64_K, 8, 0, 0, 0),
+			insn(MOV64_K, 9, 0, 0, 0),
+			// r7 = &ethertype, if r7 + 2 > data_end goto pass
+			insn(MOV64_X, 7, 2, 0, 0),
+			insn(ADD64_K, 7, 0, 0, 12),
+			insn(MOV64_X, 4, 7, 0, 0),
+			insn(ADD64_K, 4, 0, 0, 2),
+			insn(JGT_X, 4, 3, 54, 0),
+		];
+		for tag in 0..2 {
+			let to_pass = 48 - tag * 12;
+			prog.extend_from_slice(&[
+				// if *r7 is no tag goto ethertype
+				insn(LDX_H, 5, 7, 0, 0),
+				insn(JEQ_K, 5, 0, 1, dot1q),
+				insn(JNE_K, 5, 0, 21 - tag * 12, dot1ad),
+				// if r7 + 6 > data_end goto pass, r5 = id, r7 = &next ethertype
+				insn(MOV64_X, 4, 7, 0, 0),
+				insn(ADD64_K, 4, 0, 0, 6),
+				insn(JGT_X, 4, 3, to_pass, 0),
+				insn(LDX_H, 5, 7, 2, 0),
+				insn(AND64_K, 5, 0, 0, vid_mask),
+				insn(ADD64_K, 7, 0, 0, 4),
+				// if r5 != 0 { r9 = r8, r8 = r5 }
+				insn(JEQ_K, 5, 0, 2, 0),
+				insn(MOV64_X, 9, 8, 0, 0),
+				insn(MOV64_X, 8, 5, 0, 0),
+			]);
+		}
+		prog.extend_from_slice(&[
+			// ethertype: a third tag is passed as well
+			insn(LDX_H, 5, 7, 0, 0),
+			insn(JEQ_K, 5, 0, 6, ipv4),
+			insn(JNE_K, 5, 0, 27, arp),
+			// arp: if r7 + 30 > data_end goto pass, r5 = target protocol address
+			insn(MOV64_X, 4, 7, 0, 0),
+			insn(ADD64_K, 4, 0, 0, 30),
+			insn(JGT_X, 4, 3, 24, 0),
+			insn(LDX_W, 5, 7, 26, 0),
+			insn(JA, 0, 0, 4, 0),
+			// ipv4: if r7 + 22 > data_end goto pass, r5 = destination address
+			insn(MOV64_X, 4, 7, 0, 0),
+			insn(ADD64_K, 4, 0, 0, 22),
+			insn(JGT_X, 4, 3, 19, 0),
+			insn(LDX_W, 5, 7, 18, 0),
+			// if !(r0 = bpf_map_lookup_elem(addr_map, &{64, r9, r8, r5})) || !*r0 goto pass
+			insn(ST_W, 10, 0, -12, 64),
+			insn(STX_H, 10, 9, -8, 0),
+			insn(STX_H, 10, 8, -6, 0),
+			insn(STX_W, 10, 5, -4, 0),
+			insn(LD_IMM64, 1, BPF_PSEUDO_MAP_FD, 0, addr_map_fd),
+			insn(0, 0, 0, 0, 0),
+			insn(MOV64_X, 2, 10, 0, 0),
+			insn(ADD64_K, 2, 0, 0, -12),
+			insn(CALL, 0, 0, 0, BPF_FUNC_MAP_LOOKUP_ELEM),
+			insn(JEQ_K, 0, 0, 8, 0),
+			insn(LDX_W, 0, 0, 0, 0),
+			insn(JEQ_K, 0, 0, 6, 0),
+			// return bpf_redirect_map(map, ctx->rx_queue_index, XDP_PASS)
+			insn(LDX_W, 2, 6, 16, 0),
+			insn(LD_IMM64, 1, BPF_PSEUDO_MAP_FD, 0, map_fd),
+			insn(0, 0, 0, 0, 0),
+			insn(MOV64_K, 3, 0, 0, XDP_PASS),
+			insn(CALL, 0, 0, 0, BPF_FUNC_REDIRECT_MAP),
+			insn(EXIT, 0, 0, 0, 0),
+			// pass: return XDP_PASS
+			insn(MOV64_K, 0, 0, 0, XDP_PASS),
+			insn(EXIT, 0, 0, 0, 0),
+		]);
+		prog
+	}
+
+	#[repr(C)]
+	struct MapCreateAttr {
+		map_type: u32,
+		key_size: u32,
+		value_size: u32,
+		max_entries: u32,
+		map_flags: u32,
+	}
+
+	#[repr(C)]
+	struct MapElemAttr {
+		map_fd: u32,
+		_pad: u32,
+		key: u64,
+		value: u64,
+		flags: u64,
+	}
+
+	#[repr(C)]
+	struct ProgLoadAttr {
+		prog_type: u32,
+		insn_cnt: u32,
+		insns: u64,
+		license: u64,
+		log_level: u32,
+		log_size: u32,
+		log_buf: u64,
+		kern_version: u32,
+		prog_flags: u32,
+	}
+
+	#[repr(C)]
+	struct LinkCreateAttr {
+		prog_fd: u32,
+		target_ifindex: u32,
+		attach_type: u32,
+		flags: u32,
+	}
+
+	fn bpf<T>(cmd: libc::c_int, attr: &mut T) -> std::io::Result<RawFd> {
+		// SAFETY: call to c function, attr is valid for size_of::<T>() bytes
+		match unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *mut T, size_of::<T>()) } {
+			-1 => Err(IoError::last_os_error()),
+			fd => Ok(fd as _),
+		}
+	}
+
+	pub fn create_xsk_map(entries: u32) -> Result<RawFd> {
+		let mut attr = MapCreateAttr {
+			map_type: BPF_MAP_TYPE_XSKMAP,
+			key_size: 4,
+			value_size: 4,
+			max_entries: entries,
+			map_flags: 0,
+		};
+		bpf(BPF_MAP_CREATE, &mut attr).context("Create xsk map")
+	}
+
+	/// Map of the addresses the program redirects, keyed by [`AddrKey`]
+	pub fn create_addr_map(entries: u32) -> Result<RawFd> {
+		let mut attr = MapCreateAttr {
+			map_type: BPF_MAP_TYPE_LPM_TRIE,
+			key_size: size_of::<AddrKey>() as _,
+			value_size: 4,
+			max_entries: entries,
+			map_flags: BPF_F_NO_PREALLOC,
+		};
+		bpf(BPF_MAP_CREATE, &mut attr).context("Create xdp address map")
+	}
+
+	pub fn update_elem<K, V>(map_fd: RawFd, key: &K, value: &V) -> std::io::Result<()> {
+		let mut attr = MapElemAttr {
+			map_fd: map_fd as _,
+			_pad: 0,
+			key: key as *const K as u64,
+			value: value as *const V as u64,
+			flags: 0,
+		};
+		bpf(BPF_MAP_UPDATE_ELEM, &mut attr)?;
+		Ok(())
+	}
+
+	pub fn delete_elem<K>(map_fd: RawFd, key: &K) -> std::io::Result<()> {
+		let mut attr = MapElemAttr {
+			map_fd: map_fd as _,
+			_pad: 0,
+			key: key as *const K as u64,
+			value: 0,
+			flags: 0,
+		};
+		bpf(BPF_MAP_DELETE_ELEM, &mut attr)?;
+		Ok(())
+	}
+
+	pub fn load_redirect_prog(map_fd: RawFd, addr_map_fd: RawFd) -> Result<RawFd> {
+		let prog = redirect_prog(map_fd, addr_map_fd);
+		let license = b"Dual MIT/GPL\0";
+		let mut log = vec![0u8; 1 << 16];
+
+		let mut attr = ProgLoadAttr {
+			prog_type: BPF_PROG_TYPE_XDP,
+			insn_cnt: prog.len() as _,
+			insns: prog.as_ptr() as u64,
+			license: license.as_ptr() as u64,
+			log_level: 1,
+			log_size: log.len() as _,
+			log_buf: log.as_mut_ptr() as u64,
+			kern_version: 0,
+			prog_flags: 0,
+		};
+
+		bpf(BPF_PROG_LOAD, &mut attr).with_context(|| {
+			let end = log.iter().position(|&b| b == 0).unwrap_or(log.len());
+			format!("Load xdp program: {}", String::from_utf8_lossy(&log[..end]))
+		})
+	}
+
+	pub fn link_xdp(prog_fd: RawFd, ifindex: u32, flags: u32) -> Result<RawFd> {
+		let mut attr = LinkCreateAttr {
+			prog_fd: prog_fd as _,
+			target_ifindex: ifindex,
+			attach_type: BPF_XDP,
+			flags,
+		};
+		bpf(BPF_LINK_CREATE, &mut attr).context("Create xdp link")
+	}
+}
+
+/// Entries of the address map, one per filter rule
+const MAX_ADDRS: u32 = 4096;
+/// Time between kicks while all umem frames are in flight
+const TX_RETRY: Duration = Duration::from_micros(100);
+
+// libc helpers not defined in libc
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+
+const XDP_PGOFF_RX_RING: i64 = 0;
+const XDP_PGOFF_TX_RING: i64 = 0x8000_0000;
+const XDP_UMEM_PGOFF_FILL_RING: i64 = 0x1_0000_0000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: i64 = 0x1_8000_0000;
+
+const SIOCETHTOOL: libc::c_ulong = 0x8946;
+const ETHTOOL_GSTRINGS: u32 = 0x1b;
+const ETHTOOL_GSSET_INFO: u32 = 0x37;
+const ETHTOOL_GFEATURES: u32 = 0x3a;
+const ETH_SS_FEATURES: u32 = 4;
+const ETH_GSTRING_LEN: usize = 32;
+
+const XDP_COPY: u16 = 1 << 1;
+const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+const XDP_FLAGS_DRV_MODE: u32 = 1 << 2;
+
+#[repr(C)]
+struct XdpUmemReg {
+	addr: u64,
+	len: u64,
+	chunk_size: u32,
+	headroom: u32,
+	flags: u32,
+	tx_metadata_len: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct XdpRingOffset {
+	producer: u64,
+	consumer: u64,
+	desc: u64,
+	flags: u64,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct XdpMmapOffsets {
+	rx: XdpRingOffset,
+	tx: XdpRingOffset,
+	fr: XdpRingOffset,
+	cr: XdpRingOffset,
+}
+
+#[repr(C)]
+struct XdpDesc {
+	addr: u64,
+	len: u32,
+	options: u32,
+}
+
+/// `struct ifreq` with the `ifr_data` member of the union
+#[repr(C)]
+struct EthtoolIfreq {
+	ifr_name: [u8; libc::IF_NAMESIZE],
+	ifr_data: *mut u8,
+	_pad: [u8; 16],
+}
+
+#[repr(C)]
+struct SockaddrXdp {
+	sxdp_family: u16,
+	sxdp_flags: u16,
+	sxdp_ifindex: u32,
+	sxdp_queue_id: u32,
+	sxdp_shared_umem_fd: u32,
+}
@@ -40,7 +40,7 @@ async fn main_err() -> Result<()> {
 		return Ok(());
 	}
 
-	let config = match matches.opt_str("c") {
+	let filename = match matches.opt_str("c") {
 		Some(v) => v,
 		None => bail!("Missing config argument"),
 	};
@@ -48,11 +48,11 @@ async fn main_err() -> Result<()> {
 	// TODO: add -v flag(s)?
 	env_logger::init();
 
-	let config = Config::parse_file(&config).await?;
+	let config = Config::parse_file(&filename).await?;
 
 	trace!("config: {:?}", config);
 
-	config.run().await?;
+	config.run(&filename).await?;
 
 	Ok(())
 }
@@ -1,15 +1,19 @@
-use std::io::Result as IoResult;
+use std::io::{ErrorKind, Result as IoResult};
 #[cfg(target_family = "unix")]
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::pin::Pin;
 use std::sync::Arc;
-use std::task::Poll;
+use std::task::{Context, Poll};
 
 use anyhow::Result;
 use async_io::Async;
 use futures_lite::io::{AsyncRead, AsyncWrite};
 
-use crate::TunSocket;
+#[cfg(target_os = "linux")]
+use crate::framed::{PollTun, TunFramed};
+#[cfg(target_os = "linux")]
+use crate::netlink::{LinkInfo, LinkStats};
+use crate::{Mode, PacketInfo, TunOptions, TunSocket, VirtioNetHdr};
 
 #[derive(Clone)]
 pub struct AsyncTunSocket(Arc<Async<TunSocket>>);
@@ -20,10 +24,89 @@ impl AsyncTunSocket {
 		Ok(TunSocket::new(name)?.into())
 	}
 
-	/*#[cfg(target_os = "linux")]
-	pub fn set_non_blocking(&mut self) -> Result<()> {
-		self.0.get_mut().set_non_blocking()
-	}*/
+	#[cfg(target_os = "linux")]
+	pub fn with_options(name: &str, options: &TunOptions) -> Result<Self> {
+		Ok(TunSocket::with_options(name, options)?.into())
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn new_tap(name: &str) -> Result<Self> {
+		Ok(TunSocket::new_tap(name)?.into())
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn mode(&self) -> Mode {
+		self.0.get_ref().mode()
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn get_mac(&self) -> Result<[u8; 6]> {
+		self.0.get_ref().get_mac()
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn set_mac(&self, mac: [u8; 6]) -> Result<()> {
+		self.0.get_ref().set_mac(mac)
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn set_persist(&self, persist: bool) -> Result<()> {
+		self.0.get_ref().set_persist(persist)
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn set_owner(&self, uid: u32) -> Result<()> {
+		self.0.get_ref().set_owner(uid)
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn set_group(&self, gid: u32) -> Result<()> {
+		self.0.get_ref().set_group(gid)
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn set_offload(&self, flags: u32) -> Result<()> {
+		self.0.get_ref().set_offload(flags)
+	}
+
+	/// Read a packet together with its virtio-net header
+	#[cfg(target_os = "linux")]
+	pub async fn recv_vnet(&self, buf: &mut [u8]) -> IoResult<(VirtioNetHdr, usize)> {
+		self.0.read_with(|tun| tun.recv_vnet(buf)).await
+	}
+
+	/// Write a packet with the given virtio-net header
+	#[cfg(target_os = "linux")]
+	pub async fn send_vnet(&self, hdr: &VirtioNetHdr, buf: &[u8]) -> IoResult<usize> {
+		self.0.write_with(|tun| tun.send_vnet(hdr, buf)).await
+	}
+
+	/// Read a packet together with its packet information
+	#[cfg(target_os = "linux")]
+	pub async fn recv_pi(&self, buf: &mut [u8]) -> IoResult<(PacketInfo, usize)> {
+		self.0.read_with(|tun| tun.recv_pi(buf)).await
+	}
+
+	/// Write a packet with the given packet information
+	#[cfg(target_os = "linux")]
+	pub async fn send_pi(&self, pi: &PacketInfo, buf: &[u8]) -> IoResult<usize> {
+		self.0.write_with(|tun| tun.send_pi(pi, buf)).await
+	}
+
+	/// Read a packet with all headers enabled on the device
+	#[cfg(target_os = "linux")]
+	pub async fn recv_headers(
+		&self,
+		buf: &mut [u8],
+	) -> IoResult<(PacketInfo, VirtioNetHdr, usize)> {
+		self.0.read_with(|tun| tun.recv_headers(buf)).await
+	}
+
+	/// Packet oriented `Stream`/`Sink` view of the socket
+	#[cfg(target_os = "linux")]
+	pub fn into_framed(self) -> TunFramed<Self> {
+		TunFramed::new(self)
+	}
 
 	#[cfg(target_os = "linux")]
 	pub fn name(&self) -> Result<String> {
@@ -35,17 +118,42 @@ impl AsyncTunSocket {
 		self.0.get_ref().get_mtu()
 	}
 
-	/*#[cfg(target_os = "linux")]
-	pub fn set_mtu(&mut self, mtu: u32) -> Result<()> {
-		self.0.get_mut().set_mtu(mtu)
-	}*/
+	#[cfg(target_os = "linux")]
+	pub fn set_mtu(&self, mtu: u32) -> Result<()> {
+		self.0.get_ref().set_mtu(mtu)
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn set_up(&self, up: bool) -> Result<()> {
+		self.0.get_ref().set_up(up)
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn index(&self) -> Result<u32> {
+		self.0.get_ref().index()
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn link(&self) -> Result<LinkInfo> {
+		self.0.get_ref().link()
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn stats(&self) -> Result<LinkStats> {
+		self.0.get_ref().stats()
+	}
+
+	#[cfg(target_os = "linux")]
+	pub fn set_carrier(&self, carrier: bool) -> Result<()> {
+		self.0.get_ref().set_carrier(carrier)
+	}
 }
 
 #[cfg(target_family = "unix")]
 impl AsyncRead for AsyncTunSocket {
 	fn poll_read(
 		self: Pin<&mut Self>,
-		cx: &mut std::task::Context<'_>,
+		cx: &mut Context<'_>,
 		buf: &mut [u8],
 	) -> Poll<IoResult<usize>> {
 		Pin::new(&mut &*self.0).poll_read(cx, buf)
@@ -54,23 +162,54 @@ impl AsyncRead for AsyncTunSocket {
 
 #[cfg(target_family = "unix")]
 impl AsyncWrite for AsyncTunSocket {
-	fn poll_write(
-		self: Pin<&mut Self>,
-		cx: &mut std::task::Context<'_>,
-		buf: &[u8],
-	) -> Poll<IoResult<usize>> {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
 		Pin::new(&mut &*self.0).poll_write(cx, buf)
 	}
 
-	fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<IoResult<()>> {
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
 		Pin::new(&mut &*self.0).poll_flush(cx)
 	}
 
-	fn poll_close(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<IoResult<()>> {
+	fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
 		Pin::new(&mut &*self.0).poll_close(cx)
 	}
 }
 
+#[cfg(target_os = "linux")]
+impl PollTun for AsyncTunSocket {
+	fn tun(&self) -> &TunSocket {
+		self.0.get_ref()
+	}
+
+	fn poll_read_with<R>(
+		&self,
+		cx: &mut Context<'_>,
+		mut f: impl FnMut(&TunSocket) -> IoResult<R>,
+	) -> Poll<IoResult<R>> {
+		loop {
+			match f(self.0.get_ref()) {
+				Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+				res => return Poll::Ready(res),
+			}
+			futures_lite::ready!(self.0.poll_readable(cx))?;
+		}
+	}
+
+	fn poll_write_with<R>(
+		&self,
+		cx: &mut Context<'_>,
+		mut f: impl FnMut(&TunSocket) -> IoResult<R>,
+	) -> Poll<IoResult<R>> {
+		loop {
+			match f(self.0.get_ref()) {
+				Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+				res => return Poll::Ready(res),
+			}
+			futures_lite::ready!(self.0.poll_writable(cx))?;
+		}
+	}
+}
+
 impl From<TunSocket> for AsyncTunSocket {
 	fn from(socket: TunSocket) -> Self {
 		AsyncTunSocket(Arc::new(Async::new(socket).expect("oopsie whoopsie")))
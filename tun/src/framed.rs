@@ -0,0 +1,185 @@
+//! Packet oriented `Stream`/`Sink` API for the async sockets
+use std::collections::VecDeque;
+use std::io::{Error as IoError, Result as IoResult};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures_core::Stream;
+use futures_sink::Sink;
+
+use crate::packet::Packet;
+use crate::vnet::VirtioNetHdr;
+use crate::{Mode, TunSocket};
+
+/// Async socket the framed API can be built on
+pub trait PollTun: Clone + Unpin {
+	fn tun(&self) -> &TunSocket;
+
+	/// Run `f` once the socket is readable, until it does not return `WouldBlock`
+	fn poll_read_with<R>(
+		&self,
+		cx: &mut Context<'_>,
+		f: impl FnMut(&TunSocket) -> IoResult<R>,
+	) -> Poll<IoResult<R>>;
+
+	/// Run `f` once the socket is writable, until it does not return `WouldBlock`
+	fn poll_write_with<R>(
+		&self,
+		cx: &mut Context<'_>,
+		f: impl FnMut(&TunSocket) -> IoResult<R>,
+	) -> Poll<IoResult<R>>;
+}
+
+/// Big enough for GSO super packets
+const RECV_BUF: usize = 1 << 16;
+
+/// Reads and writes whole packets, see [`AsyncTunSocket::into_framed`](crate::AsyncTunSocket::into_framed)
+pub struct TunFramed<S: PollTun> {
+	reader: TunReader<S>,
+	writer: TunWriter<S>,
+}
+
+impl<S: PollTun> TunFramed<S> {
+	pub(crate) fn new(socket: S) -> Self {
+		Self {
+			reader: TunReader::new(socket.clone()),
+			writer: TunWriter::new(socket),
+		}
+	}
+
+	/// Split into independent read and write halves
+	pub fn split(self) -> (TunReader<S>, TunWriter<S>) {
+		(self.reader, self.writer)
+	}
+
+	pub fn get_ref(&self) -> &S {
+		&self.reader.socket
+	}
+}
+
+impl<S: PollTun> Stream for TunFramed<S> {
+	type Item = IoResult<Packet>;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		Pin::new(&mut self.get_mut().reader).poll_next(cx)
+	}
+}
+
+impl<S: PollTun> Sink<Packet> for TunFramed<S> {
+	type Error = IoError;
+
+	fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+		Pin::new(&mut self.get_mut().writer).poll_ready(cx)
+	}
+
+	fn start_send(self: Pin<&mut Self>, packet: Packet) -> IoResult<()> {
+		Pin::new(&mut self.get_mut().writer).start_send(packet)
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+		Pin::new(&mut self.get_mut().writer).poll_flush(cx)
+	}
+
+	fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+		Pin::new(&mut self.get_mut().writer).poll_close(cx)
+	}
+}
+
+/// Read half, yields one packet per item.
+///
+/// GSO super packets of a tun with `vnet_hdr` are segmented, so every item is a regular packet.
+pub struct TunReader<S: PollTun> {
+	socket: S,
+	buf: Vec<u8>,
+	queue: VecDeque<Packet>,
+}
+
+impl<S: PollTun> TunReader<S> {
+	fn new(socket: S) -> Self {
+		Self {
+			socket,
+			buf: vec![0u8; RECV_BUF],
+			queue: VecDeque::new(),
+		}
+	}
+}
+
+impl<S: PollTun> Stream for TunReader<S> {
+	type Item = IoResult<Packet>;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		let this = self.get_mut();
+		let mode = this.socket.tun().mode();
+
+		loop {
+			if let Some(packet) = this.queue.pop_front() {
+				return Poll::Ready(Some(Ok(packet)));
+			}
+
+			let buf = &mut this.buf;
+			let (pi, hdr, n) =
+				match ready!(this.socket.poll_read_with(cx, |tun| tun.recv_headers(buf))) {
+					Ok(res) => res,
+					Err(e) => return Poll::Ready(Some(Err(e))),
+				};
+
+			let packet = &mut this.buf[..n];
+			if mode == Mode::Tap || hdr == VirtioNetHdr::default() {
+				return Poll::Ready(Some(Ok(Packet::from_device(mode, &pi, packet.to_vec()))));
+			}
+
+			let queue = &mut this.queue;
+			hdr.segment(packet, |seg| {
+				queue.push_back(Packet::from_device(mode, &pi, seg.to_vec()))
+			})?;
+		}
+	}
+}
+
+/// Write half, every packet is written with a single syscall
+pub struct TunWriter<S: PollTun> {
+	socket: S,
+	pending: Option<Packet>,
+}
+
+impl<S: PollTun> TunWriter<S> {
+	fn new(socket: S) -> Self {
+		Self {
+			socket,
+			pending: None,
+		}
+	}
+}
+
+impl<S: PollTun> Sink<Packet> for TunWriter<S> {
+	type Error = IoError;
+
+	fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+		self.poll_flush(cx)
+	}
+
+	fn start_send(self: Pin<&mut Self>, packet: Packet) -> IoResult<()> {
+		let this = self.get_mut();
+		debug_assert!(this.pending.is_none(), "start_send without poll_ready");
+		this.pending = Some(packet);
+		Ok(())
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+		let this = self.get_mut();
+
+		if let Some(packet) = &this.pending {
+			let res = ready!(this.socket.poll_write_with(cx, |tun| {
+				tun.send_headers(&packet.info(), &VirtioNetHdr::default(), packet.as_bytes())
+			}));
+			this.pending = None;
+			res?;
+		}
+
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+		self.poll_flush(cx)
+	}
+}
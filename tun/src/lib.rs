@@ -1,11 +1,30 @@
 ///! Tun devices
 mod sync;
 
+#[cfg(target_os = "linux")]
+pub mod netlink;
+pub mod packet;
+pub mod pi;
+pub mod vnet;
+
 #[cfg(feature = "async")]
 mod r#async;
+#[cfg(all(any(feature = "async", feature = "tokio"), target_os = "linux"))]
+pub mod framed;
+#[cfg(all(feature = "tokio", target_os = "linux"))]
+mod tokio_socket;
 
+#[doc(inline)]
+pub use packet::Packet;
+#[doc(inline)]
+pub use pi::PacketInfo;
 #[cfg(feature = "async")]
 #[doc(inline)]
 pub use r#async::AsyncTunSocket;
 #[doc(inline)]
-pub use sync::TunSocket;
+pub use sync::{Mode, TunOptions, TunSocket};
+#[cfg(all(feature = "tokio", target_os = "linux"))]
+#[doc(inline)]
+pub use tokio_socket::TokioTunSocket;
+#[doc(inline)]
+pub use vnet::VirtioNetHdr;
@@ -0,0 +1,713 @@
+//! rtnetlink configuration of links, addresses and routes
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io::Error as IoError;
+use std::mem::size_of;
+use std::net::IpAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use anyhow::{bail, Context, Result};
+use libc::*;
+
+/// State of a link as reported by the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkInfo {
+	pub index: u32,
+	/// `IFF_*` flags
+	pub flags: u32,
+	pub mtu: u32,
+	pub txqueuelen: u32,
+	pub stats: LinkStats,
+}
+
+impl LinkInfo {
+	pub fn is_up(&self) -> bool {
+		self.flags & IFF_UP as u32 != 0
+	}
+
+	/// Up and ready to pass packets
+	pub fn is_running(&self) -> bool {
+		self.flags & IFF_RUNNING as u32 != 0
+	}
+
+	pub fn has_carrier(&self) -> bool {
+		self.flags & IFF_LOWER_UP as u32 != 0
+	}
+}
+
+/// Packet counters of a link, from `IFLA_STATS64`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LinkStats {
+	pub rx_packets: u64,
+	pub tx_packets: u64,
+	pub rx_bytes: u64,
+	pub tx_bytes: u64,
+	pub rx_errors: u64,
+	pub tx_errors: u64,
+	pub rx_dropped: u64,
+	pub tx_dropped: u64,
+}
+
+impl LinkStats {
+	fn parse(data: &[u8]) -> Option<Self> {
+		let u64_at = |i: usize| {
+			let mut bytes = [0u8; 8];
+			bytes.copy_from_slice(data.get(i * 8..i * 8 + 8)?);
+			Some(u64::from_ne_bytes(bytes))
+		};
+
+		// leading fields of struct rtnl_link_stats64
+		Some(Self {
+			rx_packets: u64_at(0)?,
+			tx_packets: u64_at(1)?,
+			rx_bytes: u64_at(2)?,
+			tx_bytes: u64_at(3)?,
+			rx_errors: u64_at(4)?,
+			tx_errors: u64_at(5)?,
+			rx_dropped: u64_at(6)?,
+			tx_dropped: u64_at(7)?,
+		})
+	}
+}
+
+/// Address with prefix length
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Address {
+	pub addr: IpAddr,
+	pub prefix_len: u8,
+}
+
+/// Route pointing to the link
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Route {
+	pub dst: IpAddr,
+	pub prefix_len: u8,
+	pub gateway: Option<IpAddr>,
+	/// Routing table, the main table if unset
+	pub table: Option<u32>,
+	pub metric: Option<u32>,
+}
+
+/// Settings applied together with [`Netlink::configure`], unset values are left unchanged
+#[derive(Debug, Clone, Default)]
+pub struct LinkConfig {
+	pub up: Option<bool>,
+	pub mtu: Option<u32>,
+	pub txqueuelen: Option<u32>,
+	pub addresses: Vec<Address>,
+	pub routes: Vec<Route>,
+}
+
+/// Addresses and routes added by [`Netlink::configure`], without the ones that already existed
+#[derive(Debug, Clone, Default)]
+pub struct Created {
+	pub addresses: Vec<Address>,
+	pub routes: Vec<Route>,
+}
+
+/// NETLINK_ROUTE socket
+pub struct Netlink {
+	fd: RawFd,
+	seq: u32,
+}
+
+impl Netlink {
+	pub fn new() -> Result<Self> {
+		// SAFETY: call to c function, parameters are const
+		let fd = match unsafe { socket(AF_NETLINK, SOCK_RAW | SOCK_CLOEXEC, NETLINK_ROUTE) } {
+			-1 => return Err(IoError::last_os_error()).context("Open netlink socket"),
+			fd => fd,
+		};
+		let nl = Self { fd, seq: 0 };
+
+		// SAFETY: all zero is a valid sockaddr_nl
+		let mut addr: sockaddr_nl = unsafe { std::mem::zeroed() };
+		addr.nl_family = AF_NETLINK as _;
+		// SAFETY: call to c function, addr is valid
+		let res = unsafe { bind(fd, &addr as *const _ as _, size_of::<sockaddr_nl>() as _) };
+		if res < 0 {
+			return Err(IoError::last_os_error()).context("Bind netlink socket");
+		}
+
+		// both are optional, older kernels just report errors without a message
+		let _ = nl.set_option(NETLINK_EXT_ACK, 1);
+		let _ = nl.set_option(NETLINK_CAP_ACK, 1);
+
+		Ok(nl)
+	}
+
+	/// Resolve the interface index of `name`
+	pub fn link_index(name: &str) -> Result<u32> {
+		let cname = CString::new(name).context("Invalid interface name")?;
+		// SAFETY: call to c function, cname is nul terminated
+		match unsafe { if_nametoindex(cname.as_ptr()) } {
+			0 => Err(IoError::last_os_error()).with_context(|| format!("No interface {}", name)),
+			index => Ok(index),
+		}
+	}
+
+	pub fn get_link(&mut self, index: u32) -> Result<LinkInfo> {
+		let mut msg = Message::new(RTM_GETLINK, NLM_F_REQUEST as _);
+		msg.push(&ifinfomsg(AF_UNSPEC as _, index, 0, 0));
+		let seq = self.next_seq();
+		self.send(&msg.finish(seq))?;
+
+		loop {
+			for (hdr, payload) in self.recv()? {
+				if hdr.seq != seq {
+					continue;
+				}
+				match hdr.ty {
+					RTM_NEWLINK => return parse_link(payload),
+					ty if ty == NLMSG_ERROR as u16 => {
+						parse_error(hdr.flags, payload).into_result("RTM_GETLINK")?;
+					}
+					_ => (),
+				}
+			}
+		}
+	}
+
+	pub fn set_up(&mut self, index: u32, up: bool) -> Result<()> {
+		self.request(link_message(index, Some(up), None, None), "RTM_NEWLINK")
+	}
+
+	pub fn set_mtu(&mut self, index: u32, mtu: u32) -> Result<()> {
+		self.request(link_message(index, None, Some(mtu), None), "RTM_NEWLINK")
+	}
+
+	pub fn set_txqueuelen(&mut self, index: u32, txqueuelen: u32) -> Result<()> {
+		self.request(
+			link_message(index, None, None, Some(txqueuelen)),
+			"RTM_NEWLINK",
+		)
+	}
+
+	/// Add `address` to the link, returns false if it already exists
+	pub fn add_address(&mut self, index: u32, address: &Address) -> Result<bool> {
+		self.create(address_message(index, address, true), "RTM_NEWADDR")
+	}
+
+	pub fn del_address(&mut self, index: u32, address: &Address) -> Result<()> {
+		self.request(address_message(index, address, false), "RTM_DELADDR")
+	}
+
+	/// Add `route` to the link, returns false if it already exists
+	pub fn add_route(&mut self, index: u32, route: &Route) -> Result<bool> {
+		self.create(route_message(index, route, true), "RTM_NEWROUTE")
+	}
+
+	pub fn del_route(&mut self, index: u32, route: &Route) -> Result<()> {
+		self.request(route_message(index, route, false), "RTM_DELROUTE")
+	}
+
+	/// Apply all settings of `config` to the link.
+	///
+	/// The requests are sent in one batch. If one of them fails, the already applied ones are
+	/// reverted and the first error is returned. Addresses and routes that already exist are not
+	/// ours, they are neither reverted nor part of the returned [`Created`].
+	pub fn configure(&mut self, index: u32, config: &LinkConfig) -> Result<Created> {
+		let link_change =
+			config.up.is_some() || config.mtu.is_some() || config.txqueuelen.is_some();
+		let previous = if link_change {
+			Some(self.get_link(index)?)
+		} else {
+			None
+		};
+
+		let mut batch = Vec::new();
+		let mut ops = HashMap::new();
+		let mut add = |nl: &mut Self, msg: Message, op: Op| {
+			let seq = nl.next_seq();
+			batch.extend_from_slice(&msg.finish(seq));
+			ops.insert(seq, op);
+		};
+
+		if link_change {
+			let msg = link_message(index, config.up, config.mtu, config.txqueuelen);
+			add(self, msg, Op::Link);
+		}
+		for address in &config.addresses {
+			add(
+				self,
+				address_message(index, address, true),
+				Op::Address(*address),
+			);
+		}
+		for route in &config.routes {
+			add(self, route_message(index, route, true), Op::Route(*route));
+		}
+
+		if ops.is_empty() {
+			return Ok(Created::default());
+		}
+		self.send(&batch)?;
+
+		let mut applied = Vec::new();
+		let mut error = None;
+		while !ops.is_empty() {
+			for (hdr, payload) in self.recv()? {
+				if hdr.ty != NLMSG_ERROR as u16 {
+					continue;
+				}
+				let op = match ops.remove(&hdr.seq) {
+					Some(op) => op,
+					None => continue,
+				};
+				match parse_error(hdr.flags, payload) {
+					Ack::Ok => applied.push(op),
+					Ack::Err(EEXIST, _) => (),
+					ack => {
+						if error.is_none() {
+							error = Some(ack.into_result(&op.to_string()).unwrap_err());
+						}
+					}
+				}
+			}
+		}
+
+		let error = match error {
+			Some(error) => error,
+			None => {
+				let mut created = Created::default();
+				for op in applied {
+					match op {
+						Op::Link => (),
+						Op::Address(address) => created.addresses.push(address),
+						Op::Route(route) => created.routes.push(route),
+					}
+				}
+				return Ok(created);
+			}
+		};
+
+		// best effort, the error of the original request is more interesting
+		for op in applied.iter().rev() {
+			let _ = match op {
+				Op::Link => match previous {
+					Some(prev) => self.request(
+						link_message(
+							index,
+							Some(prev.is_up()),
+							Some(prev.mtu),
+							Some(prev.txqueuelen),
+						),
+						"RTM_NEWLINK",
+					),
+					None => Ok(()),
+				},
+				Op::Address(address) => self.del_address(index, address),
+				Op::Route(route) => self.del_route(index, route),
+			};
+		}
+
+		Err(error)
+	}
+
+	fn request(&mut self, msg: Message, what: &str) -> Result<()> {
+		self.ack(msg)?.into_result(what)
+	}
+
+	/// Send a request with `NLM_F_EXCL`, returns false if the object already exists
+	fn create(&mut self, msg: Message, what: &str) -> Result<bool> {
+		match self.ack(msg)? {
+			Ack::Err(EEXIST, _) => Ok(false),
+			ack => ack.into_result(what).map(|()| true),
+		}
+	}
+
+	/// Send a single request and wait for its acknowledgement
+	fn ack(&mut self, msg: Message) -> Result<Ack> {
+		let seq = self.next_seq();
+		self.send(&msg.finish(seq))?;
+
+		loop {
+			for (hdr, payload) in self.recv()? {
+				if hdr.seq == seq && hdr.ty == NLMSG_ERROR as u16 {
+					return Ok(parse_error(hdr.flags, payload));
+				}
+			}
+		}
+	}
+
+	fn next_seq(&mut self) -> u32 {
+		self.seq = self.seq.wrapping_add(1);
+		self.seq
+	}
+
+	fn send(&self, buf: &[u8]) -> Result<()> {
+		// SAFETY: call to c function, buf is valid for its length
+		match unsafe { send(self.fd, buf.as_ptr() as _, buf.len(), 0) } {
+			-1 => Err(IoError::last_os_error()).context("Send netlink request"),
+			n if n as usize != buf.len() => bail!("Short netlink send: {} of {}", n, buf.len()),
+			_ => Ok(()),
+		}
+	}
+
+	/// Receive one datagram and split it into messages
+	fn recv(&self) -> Result<Vec<(Header, Vec<u8>)>> {
+		let mut buf = vec![0u8; RECV_BUF];
+		// SAFETY: call to c function, buf is valid for its length
+		let n = match unsafe { recv(self.fd, buf.as_mut_ptr() as _, buf.len(), 0) } {
+			-1 => return Err(IoError::last_os_error()).context("Receive netlink reply"),
+			n => n as usize,
+		};
+
+		let mut messages = Vec::new();
+		let mut buf = &buf[..n];
+		while buf.len() >= NLMSG_HDRLEN {
+			let hdr = Header::parse(buf);
+			let len = hdr.len as usize;
+			if len < NLMSG_HDRLEN || len > buf.len() {
+				bail!("Malformed netlink message");
+			}
+			messages.push((hdr, buf[NLMSG_HDRLEN..len].to_vec()));
+			buf = &buf[align(len).min(buf.len())..];
+		}
+
+		Ok(messages)
+	}
+
+	fn set_option(&self, option: c_int, value: c_int) -> Result<()> {
+		// SAFETY: call to c function, value lives for the duration of the call
+		let res = unsafe {
+			setsockopt(
+				self.fd,
+				SOL_NETLINK,
+				option,
+				&value as *const _ as _,
+				size_of::<c_int>() as _,
+			)
+		};
+		if res < 0 {
+			return Err(IoError::last_os_error()).context("setsockopt SOL_NETLINK");
+		}
+
+		Ok(())
+	}
+}
+
+impl Drop for Netlink {
+	fn drop(&mut self) {
+		// SAFETY: call to c function, self.fd is valid
+		unsafe { close(self.fd) };
+	}
+}
+
+impl AsRawFd for Netlink {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd
+	}
+}
+
+/// Request of a [`Netlink::configure`] batch
+#[derive(Debug, Clone, Copy)]
+enum Op {
+	Link,
+	Address(Address),
+	Route(Route),
+}
+
+impl std::fmt::Display for Op {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Op::Link => write!(f, "RTM_NEWLINK"),
+			Op::Address(a) => write!(f, "RTM_NEWADDR {}/{}", a.addr, a.prefix_len),
+			Op::Route(r) => write!(f, "RTM_NEWROUTE {}/{}", r.dst, r.prefix_len),
+		}
+	}
+}
+
+/// Result of an NLMSG_ERROR message
+enum Ack {
+	Ok,
+	Err(i32, Option<String>),
+}
+
+impl Ack {
+	fn into_result(self, what: &str) -> Result<()> {
+		match self {
+			Ack::Ok => Ok(()),
+			Ack::Err(errno, Some(msg)) => {
+				Err(IoError::from_raw_os_error(errno)).with_context(|| format!("{}: {}", what, msg))
+			}
+			Ack::Err(errno, None) => {
+				Err(IoError::from_raw_os_error(errno)).context(what.to_owned())
+			}
+		}
+	}
+}
+
+fn parse_error(flags: u16, payload: Vec<u8>) -> Ack {
+	if payload.len() < 4 {
+		return Ack::Err(EBADMSG, None);
+	}
+	let errno = -i32::from_ne_bytes([payload[0], payload[1], payload[2], payload[3]]);
+	if errno == 0 {
+		return Ack::Ok;
+	}
+
+	// the request follows the error code, its payload only if the ack is not capped
+	let mut offset = 4 + NLMSG_HDRLEN;
+	if flags & NLM_F_CAPPED == 0 && payload.len() >= offset {
+		offset = 4 + Header::parse(&payload[4..]).len as usize;
+	}
+
+	let mut msg = None;
+	if flags & NLM_F_ACK_TLVS != 0 && offset <= payload.len() {
+		for (ty, data) in Attrs(&payload[offset..]) {
+			if ty == NLMSGERR_ATTR_MSG {
+				let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+				msg = Some(String::from_utf8_lossy(&data[..end]).into_owned());
+			}
+		}
+	}
+
+	Ack::Err(errno, msg)
+}
+
+fn parse_link(payload: Vec<u8>) -> Result<LinkInfo> {
+	if payload.len() < IFINFOMSG_LEN {
+		bail!("Short RTM_NEWLINK message");
+	}
+
+	let u32_at =
+		|buf: &[u8], i: usize| u32::from_ne_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+	let mut info = LinkInfo {
+		index: u32_at(&payload, 4),
+		flags: u32_at(&payload, 8),
+		mtu: 0,
+		txqueuelen: 0,
+		stats: LinkStats::default(),
+	};
+
+	for (ty, data) in Attrs(&payload[IFINFOMSG_LEN..]) {
+		if data.len() < 4 {
+			continue;
+		}
+		match ty {
+			IFLA_MTU => info.mtu = u32_at(data, 0),
+			IFLA_TXQLEN => info.txqueuelen = u32_at(data, 0),
+			IFLA_STATS64 => info.stats = LinkStats::parse(data).unwrap_or_default(),
+			_ => (),
+		}
+	}
+
+	Ok(info)
+}
+
+fn link_message(
+	index: u32,
+	up: Option<bool>,
+	mtu: Option<u32>,
+	txqueuelen: Option<u32>,
+) -> Message {
+	let mut msg = Message::new(RTM_NEWLINK, (NLM_F_REQUEST | NLM_F_ACK) as _);
+	let (flags, change) = match up {
+		Some(true) => (IFF_UP as u32, IFF_UP as u32),
+		Some(false) => (0, IFF_UP as u32),
+		None => (0, 0),
+	};
+	msg.push(&ifinfomsg(AF_UNSPEC as _, index, flags, change));
+	if let Some(mtu) = mtu {
+		msg.attr(IFLA_MTU, &mtu.to_ne_bytes());
+	}
+	if let Some(txqueuelen) = txqueuelen {
+		msg.attr(IFLA_TXQLEN, &txqueuelen.to_ne_bytes());
+	}
+	msg
+}
+
+fn address_message(index: u32, address: &Address, add: bool) -> Message {
+	let mut msg = if add {
+		Message::new(
+			RTM_NEWADDR,
+			(NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL) as _,
+		)
+	} else {
+		Message::new(RTM_DELADDR, (NLM_F_REQUEST | NLM_F_ACK) as _)
+	};
+
+	let (family, octets) = family_octets(&address.addr);
+	// struct ifaddrmsg
+	let mut hdr = [0u8; 8];
+	hdr[0] = family;
+	hdr[1] = address.prefix_len;
+	hdr[3] = RT_SCOPE_UNIVERSE;
+	hdr[4..8].copy_from_slice(&index.to_ne_bytes());
+	msg.push(&hdr);
+	msg.attr(IFA_LOCAL, &octets);
+	msg.attr(IFA_ADDRESS, &octets);
+	msg
+}
+
+fn route_message(index: u32, route: &Route, add: bool) -> Message {
+	let mut msg = if add {
+		Message::new(
+			RTM_NEWROUTE,
+			(NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL) as _,
+		)
+	} else {
+		Message::new(RTM_DELROUTE, (NLM_F_REQUEST | NLM_F_ACK) as _)
+	};
+
+	let table = route.table.unwrap_or(RT_TABLE_MAIN as u32);
+	let (family, dst) = family_octets(&route.dst);
+	// struct rtmsg
+	let mut hdr = [0u8; 12];
+	hdr[0] = family;
+	hdr[1] = route.prefix_len;
+	hdr[4] = if table < 256 {
+		table as u8
+	} else {
+		RT_TABLE_UNSPEC
+	};
+	hdr[5] = RTPROT_STATIC;
+	hdr[6] = if route.gateway.is_some() {
+		RT_SCOPE_UNIVERSE
+	} else {
+		RT_SCOPE_LINK
+	};
+	hdr[7] = RTN_UNICAST;
+	msg.push(&hdr);
+
+	msg.attr(RTA_DST, &dst);
+	msg.attr(RTA_OIF, &index.to_ne_bytes());
+	msg.attr(RTA_TABLE, &table.to_ne_bytes());
+	if let Some(gateway) = route.gateway {
+		msg.attr(RTA_GATEWAY, &family_octets(&gateway).1);
+	}
+	if let Some(metric) = route.metric {
+		msg.attr(RTA_PRIORITY, &metric.to_ne_bytes());
+	}
+	msg
+}
+
+/// struct ifinfomsg
+fn ifinfomsg(family: u8, index: u32, flags: u32, change: u32) -> [u8; IFINFOMSG_LEN] {
+	let mut hdr = [0u8; IFINFOMSG_LEN];
+	hdr[0] = family;
+	hdr[4..8].copy_from_slice(&index.to_ne_bytes());
+	hdr[8..12].copy_from_slice(&flags.to_ne_bytes());
+	hdr[12..16].copy_from_slice(&change.to_ne_bytes());
+	hdr
+}
+
+fn family_octets(addr: &IpAddr) -> (u8, Vec<u8>) {
+	match addr {
+		IpAddr::V4(addr) => (AF_INET as _, addr.octets().to_vec()),
+		IpAddr::V6(addr) => (AF_INET6 as _, addr.octets().to_vec()),
+	}
+}
+
+/// Netlink request under construction
+struct Message(Vec<u8>);
+
+impl Message {
+	fn new(ty: u16, flags: u16) -> Self {
+		let mut buf = vec![0u8; NLMSG_HDRLEN];
+		buf[4..6].copy_from_slice(&ty.to_ne_bytes());
+		buf[6..8].copy_from_slice(&flags.to_ne_bytes());
+		Self(buf)
+	}
+
+	fn push(&mut self, data: &[u8]) {
+		self.0.extend_from_slice(data);
+		self.0.resize(align(self.0.len()), 0);
+	}
+
+	fn attr(&mut self, ty: u16, data: &[u8]) {
+		let len = (NLA_HDRLEN + data.len()) as u16;
+		self.0.extend_from_slice(&len.to_ne_bytes());
+		self.0.extend_from_slice(&ty.to_ne_bytes());
+		self.push(data);
+	}
+
+	fn finish(mut self, seq: u32) -> Vec<u8> {
+		let len = self.0.len() as u32;
+		self.0[0..4].copy_from_slice(&len.to_ne_bytes());
+		self.0[8..12].copy_from_slice(&seq.to_ne_bytes());
+		self.0
+	}
+}
+
+/// struct nlmsghdr, without the pid
+#[derive(Debug, Clone, Copy)]
+struct Header {
+	len: u32,
+	ty: u16,
+	flags: u16,
+	seq: u32,
+}
+
+impl Header {
+	fn parse(buf: &[u8]) -> Self {
+		Self {
+			len: u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]),
+			ty: u16::from_ne_bytes([buf[4], buf[5]]),
+			flags: u16::from_ne_bytes([buf[6], buf[7]]),
+			seq: u32::from_ne_bytes([buf[8], buf[9], buf[10], buf[11]]),
+		}
+	}
+}
+
+/// Iterator over the netlink attributes in a buffer
+struct Attrs<'a>(&'a [u8]);
+
+impl<'a> Iterator for Attrs<'a> {
+	type Item = (u16, &'a [u8]);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.0.len() < NLA_HDRLEN {
+			return None;
+		}
+		let len = u16::from_ne_bytes([self.0[0], self.0[1]]) as usize;
+		let ty = u16::from_ne_bytes([self.0[2], self.0[3]]) & NLA_TYPE_MASK;
+		if len < NLA_HDRLEN || len > self.0.len() {
+			return None;
+		}
+
+		let data = &self.0[NLA_HDRLEN..len];
+		self.0 = &self.0[align(len).min(self.0.len())..];
+		Some((ty, data))
+	}
+}
+
+fn align(len: usize) -> usize {
+	(len + 3) & !3
+}
+
+const RECV_BUF: usize = 32 * 1024;
+
+const NLMSG_HDRLEN: usize = 16;
+const NLA_HDRLEN: usize = 4;
+const IFINFOMSG_LEN: usize = 16;
+
+// libc helpers not defined in libc
+const NETLINK_EXT_ACK: c_int = 11;
+
+const NLM_F_CAPPED: u16 = 0x100;
+const NLM_F_ACK_TLVS: u16 = 0x200;
+
+const NLMSGERR_ATTR_MSG: u16 = 1;
+
+const NLA_TYPE_MASK: u16 = !(0x8000 | 0x4000);
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn link_stats_parse() {
+		// struct rtnl_link_stats64 has more fields behind the ones we read
+		let data: Vec<u8> = (1..=24u64).flat_map(|n| n.to_ne_bytes()).collect();
+		let stats = LinkStats::parse(&data).unwrap();
+		assert_eq!(stats.rx_packets, 1);
+		assert_eq!(stats.tx_bytes, 4);
+		assert_eq!(stats.tx_dropped, 8);
+
+		assert_eq!(LinkStats::parse(&data[..64]), Some(stats));
+		assert_eq!(LinkStats::parse(&data[..63]), None);
+		assert_eq!(LinkStats::parse(&[]), None);
+	}
+}
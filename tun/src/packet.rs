@@ -0,0 +1,76 @@
+//! Typed packets for the framed API
+use crate::pi::{PacketInfo, ETH_P_IP, ETH_P_IPV6};
+use crate::Mode;
+
+/// A single packet of the device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+	V4(Vec<u8>),
+	V6(Vec<u8>),
+	/// Frame of a tap device
+	Ethernet(Vec<u8>),
+	/// Neither ipv4 nor ipv6
+	Other(Vec<u8>),
+}
+
+impl Packet {
+	/// Classify an ip packet by its version
+	pub fn new(buf: Vec<u8>) -> Self {
+		match buf.first().map(|b| b >> 4) {
+			Some(4) => Packet::V4(buf),
+			Some(6) => Packet::V6(buf),
+			_ => Packet::Other(buf),
+		}
+	}
+
+	/// Classify a packet read from a device in `mode`, using the packet information if present
+	pub fn from_device(mode: Mode, pi: &PacketInfo, buf: Vec<u8>) -> Self {
+		match (mode, pi.proto) {
+			(Mode::Tap, _) => Packet::Ethernet(buf),
+			(Mode::Tun, ETH_P_IP) => Packet::V4(buf),
+			(Mode::Tun, ETH_P_IPV6) => Packet::V6(buf),
+			(Mode::Tun, _) => Packet::new(buf),
+		}
+	}
+
+	/// Packet information to write this packet with
+	pub fn info(&self) -> PacketInfo {
+		match self {
+			Packet::V4(_) => PacketInfo {
+				flags: 0,
+				proto: ETH_P_IP,
+			},
+			Packet::V6(_) => PacketInfo {
+				flags: 0,
+				proto: ETH_P_IPV6,
+			},
+			Packet::Ethernet(_) | Packet::Other(_) => PacketInfo::default(),
+		}
+	}
+
+	pub fn is_ipv4(&self) -> bool {
+		matches!(self, Packet::V4(_))
+	}
+
+	pub fn is_ipv6(&self) -> bool {
+		matches!(self, Packet::V6(_))
+	}
+
+	pub fn as_bytes(&self) -> &[u8] {
+		match self {
+			Packet::V4(buf) | Packet::V6(buf) | Packet::Ethernet(buf) | Packet::Other(buf) => buf,
+		}
+	}
+
+	pub fn into_bytes(self) -> Vec<u8> {
+		match self {
+			Packet::V4(buf) | Packet::V6(buf) | Packet::Ethernet(buf) | Packet::Other(buf) => buf,
+		}
+	}
+}
+
+impl AsRef<[u8]> for Packet {
+	fn as_ref(&self) -> &[u8] {
+		self.as_bytes()
+	}
+}
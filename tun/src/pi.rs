@@ -0,0 +1,61 @@
+//! Packet information header (`struct tun_pi`), present unless `IFF_NO_PI` is set
+
+/// The packet did not fit into the read buffer and was truncated
+pub const TUN_PKT_STRIP: u16 = 0x0001;
+
+pub const ETH_P_IP: u16 = 0x0800;
+pub const ETH_P_IPV6: u16 = 0x86dd;
+
+/// struct tun_pi, in host byte order
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PacketInfo {
+	pub flags: u16,
+	/// Ethertype of the packet
+	pub proto: u16,
+}
+
+impl PacketInfo {
+	pub const LEN: usize = 4;
+
+	pub fn parse(buf: &[u8]) -> Option<Self> {
+		if buf.len() < Self::LEN {
+			return None;
+		}
+
+		Some(Self {
+			flags: u16::from_ne_bytes([buf[0], buf[1]]),
+			proto: u16::from_be_bytes([buf[2], buf[3]]),
+		})
+	}
+
+	pub fn to_bytes(&self) -> [u8; Self::LEN] {
+		let mut buf = [0u8; Self::LEN];
+		buf[..2].copy_from_slice(&self.flags.to_ne_bytes());
+		buf[2..].copy_from_slice(&self.proto.to_be_bytes());
+		buf
+	}
+
+	/// Guess the header from the version nibble of an ip packet
+	pub fn sniff(packet: &[u8]) -> Self {
+		let proto = match packet.first().map(|b| b >> 4) {
+			Some(4) => ETH_P_IP,
+			Some(6) => ETH_P_IPV6,
+			_ => 0,
+		};
+
+		Self { flags: 0, proto }
+	}
+
+	pub fn is_ipv4(&self) -> bool {
+		self.proto == ETH_P_IP
+	}
+
+	pub fn is_ipv6(&self) -> bool {
+		self.proto == ETH_P_IPV6
+	}
+
+	/// The kernel cut the packet to the size of the read buffer
+	pub fn is_truncated(&self) -> bool {
+		self.flags & TUN_PKT_STRIP != 0
+	}
+}
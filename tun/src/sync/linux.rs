@@ -1,13 +1,41 @@
-use std::io::{Error as IoError, Result as IoResult};
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context, Result};
 use libc::*;
 
-use super::TunSocket;
+use super::{Mode, TunOptions, TunSocket};
+use crate::netlink::{LinkInfo, LinkStats, Netlink};
+use crate::pi::PacketInfo;
+use crate::vnet::VirtioNetHdr;
 
 impl TunSocket {
 	pub fn new(name: &str) -> Result<Self> {
+		Self::with_options(name, &TunOptions::default())
+	}
+
+	/// Create a tap device, which reads and writes ethernet frames
+	pub fn new_tap(name: &str) -> Result<Self> {
+		let options = TunOptions {
+			mode: Mode::Tap,
+			..Default::default()
+		};
+		Self::with_options(name, &options)
+	}
+
+	pub fn with_options(name: &str, options: &TunOptions) -> Result<Self> {
+		let mut flags = match options.mode {
+			Mode::Tun => IFF_TUN,
+			Mode::Tap => IFF_TAP,
+		} | IFF_MULTI_QUEUE;
+		if !options.packet_info {
+			flags |= IFF_NO_PI;
+		}
+		if options.vnet_hdr {
+			flags |= IFF_VNET_HDR;
+		}
+
 		// SAFETY: call to c function, parameters are const
 		let fd = match unsafe { open(b"/dev/net/tun\0".as_ptr() as _, O_RDWR) } {
 			-1 => return Err(IoError::last_os_error()).context("Open tun cotrol socket"),
@@ -18,7 +46,7 @@ impl TunSocket {
 		let mut ifr = ifreq {
 			ifr_name: [0; IF_NAMESIZE],
 			ifr_ifru: IfrIfru {
-				ifru_flags: (IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE) as _,
+				ifru_flags: flags as _,
 			},
 		};
 
@@ -33,22 +61,223 @@ impl TunSocket {
 			return Err(IoError::last_os_error()).context("Ioctl TUNSETIFF");
 		}
 
-		Ok(Self {
+		let socket = Self {
 			fd,
 			name: name.to_owned(),
-		})
+			vnet_hdr: options.vnet_hdr,
+			packet_info: options.packet_info,
+			mode: options.mode,
+		};
+
+		if let Some(owner) = options.owner {
+			socket.set_owner(owner)?;
+		}
+		if let Some(group) = options.group {
+			socket.set_group(group)?;
+		}
+		if options.persist {
+			socket.set_persist(true)?;
+		}
+
+		Ok(socket)
 	}
 
-	// todo: change back to blocking
-	pub fn set_non_blocking(&mut self) -> Result<()> {
+	/// Keep the device when it is closed, or delete it with the last close
+	pub fn set_persist(&self, persist: bool) -> Result<()> {
+		// SAFETY: call to c function, the flag is passed by value
+		if unsafe { ioctl(self.fd, TUNSETPERSIST as _, persist as c_ulong) } < 0 {
+			return Err(IoError::last_os_error()).context("Ioctl TUNSETPERSIST");
+		}
+
+		Ok(())
+	}
+
+	/// Set the user allowed to attach to the device
+	pub fn set_owner(&self, uid: uid_t) -> Result<()> {
+		// SAFETY: call to c function, the uid is passed by value
+		if unsafe { ioctl(self.fd, TUNSETOWNER as _, uid as c_ulong) } < 0 {
+			return Err(IoError::last_os_error()).context("Ioctl TUNSETOWNER");
+		}
+
+		Ok(())
+	}
+
+	/// Set the group allowed to attach to the device
+	pub fn set_group(&self, gid: gid_t) -> Result<()> {
+		// SAFETY: call to c function, the gid is passed by value
+		if unsafe { ioctl(self.fd, TUNSETGROUP as _, gid as c_ulong) } < 0 {
+			return Err(IoError::last_os_error()).context("Ioctl TUNSETGROUP");
+		}
+
+		Ok(())
+	}
+
+	pub fn mode(&self) -> Mode {
+		self.mode
+	}
+
+	/// Enable offloads, see [`crate::vnet::offload`]. Requires `vnet_hdr`.
+	pub fn set_offload(&self, flags: u32) -> Result<()> {
+		if !self.vnet_hdr {
+			bail!("Offloads require the vnet header");
+		}
+
+		// SAFETY: call to c function, the flags are passed by value
+		if unsafe { ioctl(self.fd, TUNSETOFFLOAD as _, flags as c_ulong) } < 0 {
+			return Err(IoError::last_os_error()).context("Ioctl TUNSETOFFLOAD");
+		}
+
+		Ok(())
+	}
+
+	/// Read a packet together with its virtio-net header
+	pub fn recv_vnet(&self, buf: &mut [u8]) -> IoResult<(VirtioNetHdr, usize)> {
+		self.recv_headers(buf).map(|(_, hdr, n)| (hdr, n))
+	}
+
+	/// Write a packet with the given virtio-net header
+	pub fn send_vnet(&self, hdr: &VirtioNetHdr, buf: &[u8]) -> IoResult<usize> {
+		self.send_headers(&PacketInfo::sniff(buf), hdr, buf)
+	}
+
+	/// Read a packet together with its packet information.
+	///
+	/// Without `packet_info` the header is guessed from the ip version of the packet.
+	pub fn recv_pi(&self, buf: &mut [u8]) -> IoResult<(PacketInfo, usize)> {
+		self.recv_headers(buf).map(|(pi, _, n)| (pi, n))
+	}
+
+	/// Write a packet with the given packet information
+	pub fn send_pi(&self, pi: &PacketInfo, buf: &[u8]) -> IoResult<usize> {
+		self.send_headers(pi, &VirtioNetHdr::default(), buf)
+	}
+
+	/// Read a packet with all headers enabled on the device
+	pub fn recv_headers(&self, buf: &mut [u8]) -> IoResult<(PacketInfo, VirtioNetHdr, usize)> {
+		let head_len = self.head_len();
+		if head_len == 0 {
+			let n = read_fd(self.fd, buf)?;
+			return Ok((PacketInfo::sniff(&buf[..n]), VirtioNetHdr::default(), n));
+		}
+
+		let mut head = [0u8; PacketInfo::LEN + VirtioNetHdr::LEN];
+		let n = readv_fd(self.fd, &mut head[..head_len], buf)?;
+		let n = n.saturating_sub(head_len);
+
+		// SAFETY: head is always big enough
+		let (pi, rest) = if self.packet_info {
+			(PacketInfo::parse(&head).unwrap(), &head[PacketInfo::LEN..])
+		} else {
+			(PacketInfo::sniff(&buf[..n]), &head[..])
+		};
+		let hdr = if self.vnet_hdr {
+			VirtioNetHdr::parse(rest).unwrap()
+		} else {
+			VirtioNetHdr::default()
+		};
+
+		Ok((pi, hdr, n))
+	}
+
+	/// Write a packet, the headers are only used if they are enabled on the device
+	pub fn send_headers(&self, pi: &PacketInfo, hdr: &VirtioNetHdr, buf: &[u8]) -> IoResult<usize> {
+		let head_len = self.head_len();
+		if head_len == 0 {
+			return write_fd(self.fd, buf);
+		}
+
+		let mut head = [0u8; PacketInfo::LEN + VirtioNetHdr::LEN];
+		let mut len = 0;
+		if self.packet_info {
+			head[..PacketInfo::LEN].copy_from_slice(&pi.to_bytes());
+			len += PacketInfo::LEN;
+		}
+		if self.vnet_hdr {
+			head[len..len + VirtioNetHdr::LEN].copy_from_slice(&hdr.to_bytes());
+		}
+
+		let n = writev_fd(self.fd, &head[..head_len], buf)?;
+		Ok(n.saturating_sub(head_len))
+	}
+
+	/// Length of the headers in front of every packet
+	fn head_len(&self) -> usize {
+		let mut len = 0;
+		if self.packet_info {
+			len += PacketInfo::LEN;
+		}
+		if self.vnet_hdr {
+			len += VirtioNetHdr::LEN;
+		}
+		len
+	}
+
+	/// Switch between blocking and non-blocking reads and writes
+	pub fn set_blocking(&self, blocking: bool) -> Result<()> {
+		// SAFETY: call to c function, self.fd is valid if self is valid
+		let flags = match unsafe { fcntl(self.fd, F_GETFL) } {
+			-1 => return Err(IoError::last_os_error()).context("fnctl: get flags"),
+			flags => flags,
+		};
+		let flags = if blocking {
+			flags & !O_NONBLOCK
+		} else {
+			flags | O_NONBLOCK
+		};
+
+		// SAFETY: call to c function, self.fd is valid if self is valid
+		match unsafe { fcntl(self.fd, F_SETFL, flags) } {
+			-1 => Err(IoError::last_os_error()).context("fcntl: set flags"),
+			_ => Ok(()),
+		}
+	}
+
+	pub fn set_non_blocking(&self) -> Result<()> {
+		self.set_blocking(false)
+	}
+
+	pub fn is_blocking(&self) -> Result<bool> {
 		// SAFETY: call to c function, self.fd is valid if self is valid
 		match unsafe { fcntl(self.fd, F_GETFL) } {
 			-1 => Err(IoError::last_os_error()).context("fnctl: get flags"),
-			// SAFETY: call to c function, self.fd is valid if self is valid
-			flags => match unsafe { fcntl(self.fd, F_SETFL, flags | O_NONBLOCK) } {
-				-1 => Err(IoError::last_os_error()).context("fcntl: set noblock"),
-				_ => Ok(()),
-			},
+			flags => Ok(flags & O_NONBLOCK == 0),
+		}
+	}
+
+	/// Wait until a packet can be read, returns `TimedOut` after `timeout`.
+	///
+	/// `None` waits forever, a zero duration only checks.
+	pub fn wait_readable(&self, timeout: Option<Duration>) -> IoResult<()> {
+		poll_fd(self.fd, POLLIN, timeout)
+	}
+
+	/// Wait until a packet can be written, returns `TimedOut` after `timeout`
+	pub fn wait_writable(&self, timeout: Option<Duration>) -> IoResult<()> {
+		poll_fd(self.fd, POLLOUT, timeout)
+	}
+
+	/// Read a packet, waiting at most `timeout` for it to arrive
+	pub fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> IoResult<usize> {
+		let deadline = Instant::now() + timeout;
+		loop {
+			self.wait_readable(Some(deadline.saturating_duration_since(Instant::now())))?;
+			match self.read_int(buf) {
+				// another reader was faster, or the socket is non-blocking
+				Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+				res => return res,
+			}
+		}
+	}
+
+	/// Write a packet, waiting at most `timeout` for the device to accept it
+	pub fn write_timeout(&self, buf: &[u8], timeout: Duration) -> IoResult<usize> {
+		let deadline = Instant::now() + timeout;
+		loop {
+			self.wait_writable(Some(deadline.saturating_duration_since(Instant::now())))?;
+			match self.write_int(buf) {
+				Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+				res => return res,
+			}
 		}
 	}
 
@@ -56,54 +285,113 @@ impl TunSocket {
 		Ok(self.name.clone())
 	}
 
+	/// Interface index of the device
+	pub fn index(&self) -> Result<u32> {
+		Netlink::link_index(&self.name)
+	}
+
+	/// Flags, MTU and packet counters of the device
+	pub fn link(&self) -> Result<LinkInfo> {
+		Netlink::new()?.get_link(self.index()?)
+	}
+
+	/// RX/TX counters of the device, rx are the packets written to the socket
+	pub fn stats(&self) -> Result<LinkStats> {
+		Ok(self.link()?.stats)
+	}
+
 	/// Get the current MTU value
 	pub fn get_mtu(&self) -> Result<u32> {
+		Ok(self.link()?.mtu)
+	}
+
+	/// Signal carrier loss, the kernel keeps the device down and stops routing packets to it
+	pub fn set_carrier(&self, carrier: bool) -> Result<()> {
+		let carrier = carrier as c_int;
+		// SAFETY: call to c function, carrier lives for the duration of the call
+		if unsafe { ioctl(self.fd, TUNSETCARRIER as _, &carrier) } < 0 {
+			return Err(IoError::last_os_error()).context("Ioctl TUNSETCARRIER");
+		}
+
+		Ok(())
+	}
+
+	pub fn set_mtu(&self, mtu: u32) -> Result<()> {
+		Netlink::new()?.set_mtu(self.index()?, mtu)
+	}
+
+	/// Set the interface administratively up or down
+	pub fn set_up(&self, up: bool) -> Result<()> {
+		Netlink::new()?.set_up(self.index()?, up)
+	}
+
+	/// Get the hardware address of a tap device
+	pub fn get_mac(&self) -> Result<[u8; 6]> {
+		if self.mode != Mode::Tap {
+			bail!("Only tap devices have a hardware address");
+		}
+
 		// SAFETY: call to C function with checked arguments
-		let fd = match unsafe { socket(AF_INET, SOCK_STREAM, IPPROTO_IP) } {
+		let fd = match unsafe { socket(AF_INET, SOCK_DGRAM, IPPROTO_IP) } {
 			-1 => return Err(IoError::last_os_error()).context("opening control socket"),
 			fd => fd,
 		};
 
-		let name = self.name()?;
-		let iface_name: &[u8] = name.as_ref();
+		let iface_name: &[u8] = self.name.as_ref();
 		let mut ifr = ifreq {
 			ifr_name: [0; IF_NAMESIZE],
-			ifr_ifru: IfrIfru { ifru_mtu: 0 },
+			// SAFETY: all zero is a valid sockaddr
+			ifr_ifru: IfrIfru {
+				ifru_hwaddr: unsafe { std::mem::zeroed() },
+			},
 		};
 
 		ifr.ifr_name[..iface_name.len()].copy_from_slice(iface_name);
 
 		// SAFETY: call to c function, fd and ifr is valid
-		if unsafe { ioctl(fd, SIOCGIFMTU as _, &mut ifr) } < 0 {
-			return Err(IoError::last_os_error()).context("ioctl getting mtu");
-		}
-
+		let res = unsafe { ioctl(fd, SIOCGIFHWADDR as _, &mut ifr) };
 		// SAFETY: call to c function, fd is valid
 		unsafe { close(fd) };
+		if res < 0 {
+			return Err(IoError::last_os_error()).context("ioctl getting hardware address");
+		}
 
+		let mut mac = [0u8; 6];
 		// SAFETY: accessing a union, ifr is valid
-		Ok(unsafe { ifr.ifr_ifru.ifru_mtu } as _)
+		let data = unsafe { ifr.ifr_ifru.ifru_hwaddr.sa_data };
+		for (m, d) in mac.iter_mut().zip(data.iter()) {
+			*m = *d as u8;
+		}
+
+		Ok(mac)
 	}
 
-	pub fn set_mtu(&mut self, mtu: u32) -> Result<()> {
-		// SAFETY: call to C function with checked arguments
-		let fd = match unsafe { socket(AF_INET, SOCK_STREAM, IPPROTO_IP) } {
-			-1 => return Err(IoError::last_os_error()).context("opening control socket"),
-			fd => fd,
-		};
+	/// Set the hardware address of a tap device
+	pub fn set_mac(&self, mac: [u8; 6]) -> Result<()> {
+		if self.mode != Mode::Tap {
+			bail!("Only tap devices have a hardware address");
+		}
+
+		// SAFETY: all zero is a valid sockaddr
+		let mut hwaddr: sockaddr = unsafe { std::mem::zeroed() };
+		hwaddr.sa_family = ARPHRD_ETHER;
+		for (d, m) in hwaddr.sa_data.iter_mut().zip(mac.iter()) {
+			*d = *m as _;
+		}
 
-		let name = self.name()?;
-		let iface_name: &[u8] = name.as_ref();
+		let iface_name: &[u8] = self.name.as_ref();
 		let mut ifr = ifreq {
 			ifr_name: [0; IF_NAMESIZE],
-			ifr_ifru: IfrIfru { ifru_mtu: mtu as _ },
+			ifr_ifru: IfrIfru {
+				ifru_hwaddr: hwaddr,
+			},
 		};
 
 		ifr.ifr_name[..iface_name.len()].copy_from_slice(iface_name);
 
-		let res = unsafe { libc::ioctl(fd, SIOCSIFMTU as _, &mut ifr) };
-		if res < 0 {
-			return Err(IoError::last_os_error()).context("set mtu");
+		// SAFETY: call to c function, self.fd and ifr is valid
+		if unsafe { ioctl(self.fd, SIOCSIFHWADDR as _, &mut ifr) } < 0 {
+			return Err(IoError::last_os_error()).context("ioctl setting hardware address");
 		}
 
 		Ok(())
@@ -118,12 +406,12 @@ impl TunSocket {
 	}*/
 
 	// TODO: special handling for mac (if ever, needs AF number)
-	pub(crate) fn write_int(&mut self, buf: &[u8]) -> IoResult<usize> {
-		write_fd(self.fd, buf)
+	pub(crate) fn write_int(&self, buf: &[u8]) -> IoResult<usize> {
+		self.send_vnet(&VirtioNetHdr::default(), buf)
 	}
 
-	pub(crate) fn read_int(&mut self, buf: &mut [u8]) -> IoResult<usize> {
-		read_fd(self.fd, buf)
+	pub(crate) fn read_int(&self, buf: &mut [u8]) -> IoResult<usize> {
+		self.recv_vnet(buf).map(|(_, n)| n)
 	}
 }
 
@@ -141,6 +429,70 @@ pub(crate) fn write_fd(fd: RawFd, buf: &[u8]) -> IoResult<usize> {
 	}
 }
 
+fn poll_fd(fd: RawFd, events: c_short, timeout: Option<Duration>) -> IoResult<()> {
+	let timeout = match timeout {
+		Some(timeout) => timeout.as_millis().min(c_int::MAX as u128) as c_int,
+		None => -1,
+	};
+	let mut pfd = pollfd {
+		fd,
+		events,
+		revents: 0,
+	};
+
+	loop {
+		// SAFETY: call to c function, pfd is valid
+		match unsafe { poll(&mut pfd, 1, timeout) } {
+			-1 => {
+				let err = IoError::last_os_error();
+				if err.kind() != ErrorKind::Interrupted {
+					return Err(err);
+				}
+			}
+			0 => return Err(IoError::new(ErrorKind::TimedOut, "tun poll timed out")),
+			_ => return Ok(()),
+		}
+	}
+}
+
+fn readv_fd(fd: RawFd, head: &mut [u8], buf: &mut [u8]) -> IoResult<usize> {
+	let iov = [
+		iovec {
+			iov_base: head.as_mut_ptr() as _,
+			iov_len: head.len(),
+		},
+		iovec {
+			iov_base: buf.as_mut_ptr() as _,
+			iov_len: buf.len(),
+		},
+	];
+
+	// SAFETY: call to c function, both iovecs point to valid buffers
+	match unsafe { readv(fd, iov.as_ptr(), iov.len() as _) } {
+		-1 => Err(IoError::last_os_error()),
+		n => Ok(n as usize),
+	}
+}
+
+fn writev_fd(fd: RawFd, head: &[u8], buf: &[u8]) -> IoResult<usize> {
+	let iov = [
+		iovec {
+			iov_base: head.as_ptr() as _,
+			iov_len: head.len(),
+		},
+		iovec {
+			iov_base: buf.as_ptr() as _,
+			iov_len: buf.len(),
+		},
+	];
+
+	// SAFETY: call to c function, both iovecs point to valid buffers
+	match unsafe { writev(fd, iov.as_ptr(), iov.len() as _) } {
+		-1 => Err(IoError::last_os_error()),
+		n => Ok(n as usize),
+	}
+}
+
 impl Drop for TunSocket {
 	fn drop(&mut self) {
 		unsafe { close(self.fd) };
@@ -155,10 +507,16 @@ impl AsRawFd for TunSocket {
 
 // libc helpers not defined in libc
 const TUNSETIFF: u64 = 0x4004_54ca;
+const TUNSETPERSIST: u64 = 0x4004_54cb;
+const TUNSETOWNER: u64 = 0x4004_54cc;
+const TUNSETGROUP: u64 = 0x4004_54ce;
+const TUNSETOFFLOAD: u64 = 0x4004_54d0;
+const TUNSETCARRIER: u64 = 0x4004_54e2;
 
 #[repr(C)]
 union IfrIfru {
 	ifru_addr: sockaddr,
+	ifru_hwaddr: sockaddr,
 	ifru_addr_v4: sockaddr_in,
 	ifru_addr_v6: sockaddr_in,
 	ifru_dstaddr: sockaddr,
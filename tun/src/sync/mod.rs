@@ -10,6 +10,52 @@ pub struct TunSocket {
 
 	#[cfg(target_family = "unix")]
 	fd: RawFd,
+
+	/// packets are prefixed with a virtio-net header
+	vnet_hdr: bool,
+
+	/// packets are prefixed with a tun_pi header
+	packet_info: bool,
+
+	mode: Mode,
+}
+
+/// Layer of the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+	/// ip packets (`IFF_TUN`)
+	#[default]
+	Tun,
+	/// ethernet frames (`IFF_TAP`)
+	Tap,
+}
+
+/// Options used to create the device
+#[derive(Debug, Clone, Default)]
+pub struct TunOptions {
+	/// Prefix every packet with a [`VirtioNetHdr`](crate::VirtioNetHdr), required for offloads.
+	///
+	/// The `Read` and `Write` implementations strip and add the header, use
+	/// [`TunSocket::recv_vnet`] and [`TunSocket::send_vnet`] to access it.
+	pub vnet_hdr: bool,
+
+	/// Create a tun or a tap device
+	pub mode: Mode,
+
+	/// Prefix every packet with a [`PacketInfo`](crate::PacketInfo) header.
+	///
+	/// The `Read` and `Write` implementations strip and add the header, use
+	/// [`TunSocket::recv_pi`] and [`TunSocket::send_pi`] to access it.
+	pub packet_info: bool,
+
+	/// Keep the device after the last socket is closed
+	pub persist: bool,
+
+	/// Allow this user to attach to the device without `CAP_NET_ADMIN`
+	pub owner: Option<u32>,
+
+	/// Allow this group to attach to the device without `CAP_NET_ADMIN`
+	pub group: Option<u32>,
 }
 
 #[cfg(target_family = "unix")]
@@ -23,7 +69,7 @@ impl Read for TunSocket {
 impl<'a> Read for &'a TunSocket {
 	#[cfg(target_os = "linux")]
 	fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
-		linux::read_fd(self.fd, buf)
+		self.read_int(buf)
 	}
 
 	#[cfg(not(target_os = "linux"))]
@@ -38,8 +84,9 @@ impl Write for TunSocket {
 		self.write_int(buf)
 	}
 
+	/// Packets are written with a single syscall, there is nothing to flush
 	fn flush(&mut self) -> IoResult<()> {
-		todo!()
+		Ok(())
 	}
 }
 
@@ -47,7 +94,7 @@ impl Write for TunSocket {
 impl<'a> Write for &'a TunSocket {
 	#[cfg(target_os = "linux")]
 	fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-		linux::write_fd(self.fd, buf)
+		self.write_int(buf)
 	}
 
 	#[cfg(not(target_os = "linux"))]
@@ -56,6 +103,6 @@ impl<'a> Write for &'a TunSocket {
 	}
 
 	fn flush(&mut self) -> IoResult<()> {
-		todo!()
+		Ok(())
 	}
 }
@@ -0,0 +1,218 @@
+use std::convert::TryFrom;
+use std::io::Result as IoResult;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use anyhow::{Context as _, Result};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::framed::{PollTun, TunFramed};
+use crate::netlink::{LinkInfo, LinkStats};
+use crate::{Mode, PacketInfo, TunOptions, TunSocket, VirtioNetHdr};
+
+/// [`AsyncTunSocket`](crate::AsyncTunSocket) for the tokio runtime, has to be created inside
+/// of a runtime
+#[derive(Clone)]
+pub struct TokioTunSocket(Arc<AsyncFd<TunSocket>>);
+
+impl TokioTunSocket {
+	pub fn new(name: &str) -> Result<Self> {
+		Self::try_from(TunSocket::new(name)?)
+	}
+
+	pub fn with_options(name: &str, options: &TunOptions) -> Result<Self> {
+		Self::try_from(TunSocket::with_options(name, options)?)
+	}
+
+	pub fn new_tap(name: &str) -> Result<Self> {
+		Self::try_from(TunSocket::new_tap(name)?)
+	}
+
+	pub fn mode(&self) -> Mode {
+		self.0.get_ref().mode()
+	}
+
+	pub fn get_mac(&self) -> Result<[u8; 6]> {
+		self.0.get_ref().get_mac()
+	}
+
+	pub fn set_mac(&self, mac: [u8; 6]) -> Result<()> {
+		self.0.get_ref().set_mac(mac)
+	}
+
+	pub fn set_persist(&self, persist: bool) -> Result<()> {
+		self.0.get_ref().set_persist(persist)
+	}
+
+	pub fn set_owner(&self, uid: u32) -> Result<()> {
+		self.0.get_ref().set_owner(uid)
+	}
+
+	pub fn set_group(&self, gid: u32) -> Result<()> {
+		self.0.get_ref().set_group(gid)
+	}
+
+	pub fn set_offload(&self, flags: u32) -> Result<()> {
+		self.0.get_ref().set_offload(flags)
+	}
+
+	/// Read a packet together with its virtio-net header
+	pub async fn recv_vnet(&self, buf: &mut [u8]) -> IoResult<(VirtioNetHdr, usize)> {
+		self.read_with(|tun| tun.recv_vnet(buf)).await
+	}
+
+	/// Write a packet with the given virtio-net header
+	pub async fn send_vnet(&self, hdr: &VirtioNetHdr, buf: &[u8]) -> IoResult<usize> {
+		self.write_with(|tun| tun.send_vnet(hdr, buf)).await
+	}
+
+	/// Read a packet together with its packet information
+	pub async fn recv_pi(&self, buf: &mut [u8]) -> IoResult<(PacketInfo, usize)> {
+		self.read_with(|tun| tun.recv_pi(buf)).await
+	}
+
+	/// Write a packet with the given packet information
+	pub async fn send_pi(&self, pi: &PacketInfo, buf: &[u8]) -> IoResult<usize> {
+		self.write_with(|tun| tun.send_pi(pi, buf)).await
+	}
+
+	/// Read a packet with all headers enabled on the device
+	pub async fn recv_headers(
+		&self,
+		buf: &mut [u8],
+	) -> IoResult<(PacketInfo, VirtioNetHdr, usize)> {
+		self.read_with(|tun| tun.recv_headers(buf)).await
+	}
+
+	/// Packet oriented `Stream`/`Sink` view of the socket
+	pub fn into_framed(self) -> TunFramed<Self> {
+		TunFramed::new(self)
+	}
+
+	pub fn name(&self) -> Result<String> {
+		self.0.get_ref().name()
+	}
+
+	pub fn get_mtu(&self) -> Result<u32> {
+		self.0.get_ref().get_mtu()
+	}
+
+	pub fn set_mtu(&self, mtu: u32) -> Result<()> {
+		self.0.get_ref().set_mtu(mtu)
+	}
+
+	pub fn set_up(&self, up: bool) -> Result<()> {
+		self.0.get_ref().set_up(up)
+	}
+
+	pub fn index(&self) -> Result<u32> {
+		self.0.get_ref().index()
+	}
+
+	pub fn link(&self) -> Result<LinkInfo> {
+		self.0.get_ref().link()
+	}
+
+	pub fn stats(&self) -> Result<LinkStats> {
+		self.0.get_ref().stats()
+	}
+
+	pub fn set_carrier(&self, carrier: bool) -> Result<()> {
+		self.0.get_ref().set_carrier(carrier)
+	}
+
+	async fn read_with<R>(&self, mut f: impl FnMut(&TunSocket) -> IoResult<R>) -> IoResult<R> {
+		loop {
+			let mut guard = self.0.readable().await?;
+			if let Ok(res) = guard.try_io(|fd| f(fd.get_ref())) {
+				return res;
+			}
+		}
+	}
+
+	async fn write_with<R>(&self, mut f: impl FnMut(&TunSocket) -> IoResult<R>) -> IoResult<R> {
+		loop {
+			let mut guard = self.0.writable().await?;
+			if let Ok(res) = guard.try_io(|fd| f(fd.get_ref())) {
+				return res;
+			}
+		}
+	}
+}
+
+impl PollTun for TokioTunSocket {
+	fn tun(&self) -> &TunSocket {
+		self.0.get_ref()
+	}
+
+	fn poll_read_with<R>(
+		&self,
+		cx: &mut Context<'_>,
+		mut f: impl FnMut(&TunSocket) -> IoResult<R>,
+	) -> Poll<IoResult<R>> {
+		loop {
+			let mut guard = ready!(self.0.poll_read_ready(cx))?;
+			if let Ok(res) = guard.try_io(|fd| f(fd.get_ref())) {
+				return Poll::Ready(res);
+			}
+		}
+	}
+
+	fn poll_write_with<R>(
+		&self,
+		cx: &mut Context<'_>,
+		mut f: impl FnMut(&TunSocket) -> IoResult<R>,
+	) -> Poll<IoResult<R>> {
+		loop {
+			let mut guard = ready!(self.0.poll_write_ready(cx))?;
+			if let Ok(res) = guard.try_io(|fd| f(fd.get_ref())) {
+				return Poll::Ready(res);
+			}
+		}
+	}
+}
+
+impl AsyncRead for TokioTunSocket {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<IoResult<()>> {
+		let n = ready!(self.poll_read_with(cx, |tun| tun.read_int(buf.initialize_unfilled())))?;
+		buf.advance(n);
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl AsyncWrite for TokioTunSocket {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+		self.poll_write_with(cx, |tun| tun.write_int(buf))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl TryFrom<TunSocket> for TokioTunSocket {
+	type Error = anyhow::Error;
+
+	fn try_from(socket: TunSocket) -> Result<Self> {
+		socket.set_non_blocking()?;
+		let fd = AsyncFd::new(socket).context("Register tun with the tokio reactor")?;
+		Ok(Self(Arc::new(fd)))
+	}
+}
+
+impl AsRawFd for TokioTunSocket {
+	fn as_raw_fd(&self) -> RawFd {
+		self.0.as_raw_fd()
+	}
+}
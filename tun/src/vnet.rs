@@ -0,0 +1,504 @@
+//! virtio-net header used with `IFF_VNET_HDR`
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+
+/// Offload flags for [`TunSocket::set_offload`](crate::TunSocket::set_offload)
+pub mod offload {
+	/// The kernel may hand us packets with a partial checksum
+	pub const CSUM: u32 = 0x01;
+	/// TCP segmentation offload for ipv4
+	pub const TSO4: u32 = 0x02;
+	/// TCP segmentation offload for ipv6
+	pub const TSO6: u32 = 0x04;
+	/// TCP segmentation offload with ECN bits
+	pub const TSO_ECN: u32 = 0x08;
+	/// UDP segmentation offload for ipv4
+	pub const USO4: u32 = 0x20;
+	/// UDP segmentation offload for ipv6
+	pub const USO6: u32 = 0x40;
+}
+
+pub const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+
+pub const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+pub const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+pub const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+pub const VIRTIO_NET_HDR_GSO_UDP_L4: u8 = 5;
+pub const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+/// struct virtio_net_hdr, in host byte order
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VirtioNetHdr {
+	pub flags: u8,
+	pub gso_type: u8,
+	pub hdr_len: u16,
+	pub gso_size: u16,
+	pub csum_start: u16,
+	pub csum_offset: u16,
+}
+
+impl VirtioNetHdr {
+	pub const LEN: usize = 10;
+
+	pub fn parse(buf: &[u8]) -> Option<Self> {
+		if buf.len() < Self::LEN {
+			return None;
+		}
+
+		let u16_at = |i: usize| u16::from_ne_bytes([buf[i], buf[i + 1]]);
+		Some(Self {
+			flags: buf[0],
+			gso_type: buf[1],
+			hdr_len: u16_at(2),
+			gso_size: u16_at(4),
+			csum_start: u16_at(6),
+			csum_offset: u16_at(8),
+		})
+	}
+
+	pub fn to_bytes(&self) -> [u8; Self::LEN] {
+		let mut buf = [0u8; Self::LEN];
+		buf[0] = self.flags;
+		buf[1] = self.gso_type;
+		buf[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+		buf[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+		buf[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+		buf[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+		buf
+	}
+
+	/// Packet is a GSO super packet, which has to be segmented
+	pub fn is_gso(&self) -> bool {
+		self.gso_type & !VIRTIO_NET_HDR_GSO_ECN != VIRTIO_NET_HDR_GSO_NONE
+	}
+
+	/// Turn `packet` into one or more regular packets with complete checksums.
+	///
+	/// Super packets are split into segments of at most `gso_size` payload bytes, every segment
+	/// is handed to `f`.
+	pub fn segment(&self, packet: &mut [u8], mut f: impl FnMut(&[u8])) -> IoResult<()> {
+		if !self.is_gso() {
+			if self.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+				self.complete_csum(packet)?;
+			}
+			f(packet);
+			return Ok(());
+		}
+
+		let ip = IpHeader::parse(packet)?;
+		let (l4_len, tcp) = match self.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+			VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_TCPV6 => {
+				let off = (get(packet, ip.len + 12)? >> 4) as usize * 4;
+				if off < TCP_HDR_LEN {
+					return Err(invalid("tcp header too short"));
+				}
+				(off, true)
+			}
+			VIRTIO_NET_HDR_GSO_UDP_L4 => (8, false),
+			other => return Err(invalid(&format!("unsupported gso type {}", other))),
+		};
+		let hdr_len = ip.len + l4_len;
+		if hdr_len > packet.len() || self.gso_size == 0 {
+			return Err(invalid("invalid gso packet"));
+		}
+
+		let (headers, payload) = packet.split_at(hdr_len);
+		let chunks = payload.chunks(self.gso_size as usize);
+		let count = chunks.len();
+		let mut seg = Vec::with_capacity(hdr_len + self.gso_size as usize);
+
+		for (i, chunk) in chunks.enumerate() {
+			seg.clear();
+			seg.extend_from_slice(headers);
+			seg.extend_from_slice(chunk);
+
+			let l4 = ip.len;
+			let l4_total = seg.len() - l4;
+			ip.fix_segment(&mut seg, i as u16);
+
+			if tcp {
+				let seq = u32::from_be_bytes([seg[l4 + 4], seg[l4 + 5], seg[l4 + 6], seg[l4 + 7]]);
+				let seq = seq.wrapping_add((i * self.gso_size as usize) as u32);
+				seg[l4 + 4..l4 + 8].copy_from_slice(&seq.to_be_bytes());
+				// FIN and PSH only on the last, CWR only on the first segment
+				if i + 1 != count {
+					seg[l4 + 13] &= !(TCP_FIN | TCP_PSH);
+				}
+				if i != 0 {
+					seg[l4 + 13] &= !TCP_CWR;
+				}
+				seg[l4 + 16..l4 + 18].copy_from_slice(&[0, 0]);
+				let csum = ip.l4_checksum(&seg, IPPROTO_TCP, l4_total);
+				seg[l4 + 16..l4 + 18].copy_from_slice(&csum.to_be_bytes());
+			} else {
+				seg[l4 + 4..l4 + 6].copy_from_slice(&(l4_total as u16).to_be_bytes());
+				seg[l4 + 6..l4 + 8].copy_from_slice(&[0, 0]);
+				let csum = match ip.l4_checksum(&seg, IPPROTO_UDP, l4_total) {
+					0 => 0xffff,
+					csum => csum,
+				};
+				seg[l4 + 6..l4 + 8].copy_from_slice(&csum.to_be_bytes());
+			}
+
+			f(&seg);
+		}
+
+		Ok(())
+	}
+
+	/// Merge ipv6 udp datagrams of one flow at the start of `packets` into a super packet in `buf`,
+	/// the reverse of [`segment`](Self::segment).
+	///
+	/// All but the last datagram need the same payload size. Returns the header for the super
+	/// packet and the number of merged datagrams, `None` if the first one can't be merged.
+	pub fn coalesce_udp(packets: &[impl AsRef<[u8]>], buf: &mut Vec<u8>) -> Option<(Self, usize)> {
+		let first = packets.first()?.as_ref();
+		let gso_size = udp6_payload(first)?;
+		if gso_size == 0 {
+			return None;
+		}
+
+		buf.clear();
+		buf.extend_from_slice(first);
+		let mut count = 1;
+		for packet in packets[1..].iter().map(AsRef::as_ref) {
+			let size = match udp6_payload(packet) {
+				Some(size) if size > 0 && size <= gso_size => size,
+				_ => break,
+			};
+			// same addresses, traffic class, flow label, hop limit and ports
+			if packet[..4] != first[..4]
+				|| packet[6..UDP6_HDR_LEN - 4] != first[6..UDP6_HDR_LEN - 4]
+				|| buf.len() + size > IPV6_HDR_LEN + MAX_UDP_LEN
+				|| count == UDP_MAX_SEGMENTS
+			{
+				break;
+			}
+
+			buf.extend_from_slice(&packet[UDP6_HDR_LEN..]);
+			count += 1;
+			// only the last segment may be shorter
+			if size < gso_size {
+				break;
+			}
+		}
+		if count == 1 {
+			return None;
+		}
+
+		let udp_len = (buf.len() - IPV6_HDR_LEN) as u16;
+		buf[4..6].copy_from_slice(&udp_len.to_be_bytes());
+		buf[44..46].copy_from_slice(&udp_len.to_be_bytes());
+		let pseudo = sum(&buf[8..40], 0) + IPPROTO_UDP as u32 + udp_len as u32;
+		buf[46..48].copy_from_slice(&fold(pseudo).to_be_bytes());
+
+		let hdr = Self {
+			flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+			gso_type: VIRTIO_NET_HDR_GSO_UDP_L4,
+			hdr_len: UDP6_HDR_LEN as u16,
+			gso_size: gso_size as u16,
+			csum_start: IPV6_HDR_LEN as u16,
+			csum_offset: 6,
+		};
+		Some((hdr, count))
+	}
+
+	/// Fold the checksum over the data from `csum_start`, the field holds the pseudo header sum
+	fn complete_csum(&self, packet: &mut [u8]) -> IoResult<()> {
+		let start = self.csum_start as usize;
+		let field = start + self.csum_offset as usize;
+		if field + 2 > packet.len() {
+			return Err(invalid("checksum offset out of bounds"));
+		}
+
+		let csum = !fold(sum(&packet[start..], 0));
+		packet[field..field + 2].copy_from_slice(&csum.to_be_bytes());
+		Ok(())
+	}
+}
+
+/// Minimal view of the ip header of a super packet
+#[derive(Debug, Clone, Copy)]
+struct IpHeader {
+	v4: bool,
+	len: usize,
+}
+
+impl IpHeader {
+	fn parse(packet: &[u8]) -> IoResult<Self> {
+		match get(packet, 0)? >> 4 {
+			4 => {
+				let len = (get(packet, 0)? & 0x0f) as usize * 4;
+				if len < IPV4_HDR_LEN {
+					return Err(invalid("ipv4 header too short"));
+				}
+				Ok(Self { v4: true, len })
+			}
+			// extension headers are not used with gso
+			6 => Ok(Self { v4: false, len: 40 }),
+			v => Err(invalid(&format!("unknown ip version {}", v))),
+		}
+	}
+
+	/// Update length, id and checksum of the ip header of segment `i`
+	fn fix_segment(&self, seg: &mut [u8], i: u16) {
+		let total = seg.len();
+		if self.v4 {
+			seg[2..4].copy_from_slice(&(total as u16).to_be_bytes());
+			let id = u16::from_be_bytes([seg[4], seg[5]]).wrapping_add(i);
+			seg[4..6].copy_from_slice(&id.to_be_bytes());
+			seg[10..12].copy_from_slice(&[0, 0]);
+			let csum = !fold(sum(&seg[..self.len], 0));
+			seg[10..12].copy_from_slice(&csum.to_be_bytes());
+		} else {
+			seg[4..6].copy_from_slice(&((total - self.len) as u16).to_be_bytes());
+		}
+	}
+
+	fn l4_checksum(&self, seg: &[u8], proto: u8, l4_len: usize) -> u16 {
+		let pseudo = if self.v4 {
+			sum(&seg[12..20], 0)
+		} else {
+			sum(&seg[8..40], 0)
+		};
+		let pseudo = pseudo + proto as u32 + l4_len as u32;
+		!fold(sum(&seg[self.len..], pseudo))
+	}
+}
+
+/// Payload size of an ipv6 udp datagram without extension headers
+fn udp6_payload(packet: &[u8]) -> Option<usize> {
+	if packet.len() < UDP6_HDR_LEN || packet[0] >> 4 != 6 || packet[6] != IPPROTO_UDP {
+		return None;
+	}
+	let payload_len = u16::from_be_bytes([packet[4], packet[5]]) as usize;
+	let udp_len = u16::from_be_bytes([packet[44], packet[45]]) as usize;
+	if payload_len != packet.len() - IPV6_HDR_LEN || udp_len != payload_len {
+		return None;
+	}
+
+	Some(packet.len() - UDP6_HDR_LEN)
+}
+
+/// One's complement sum over 16 bit words
+fn sum(data: &[u8], mut acc: u32) -> u32 {
+	let mut words = data.chunks_exact(2);
+	for word in &mut words {
+		acc = acc.wrapping_add(u16::from_be_bytes([word[0], word[1]]) as u32);
+		acc = (acc & 0xffff) + (acc >> 16);
+	}
+	if let [last] = words.remainder() {
+		acc = acc.wrapping_add((*last as u32) << 8);
+	}
+	acc
+}
+
+fn fold(mut acc: u32) -> u16 {
+	while acc > 0xffff {
+		acc = (acc & 0xffff) + (acc >> 16);
+	}
+	acc as u16
+}
+
+fn get(packet: &[u8], i: usize) -> IoResult<u8> {
+	packet
+		.get(i)
+		.copied()
+		.ok_or_else(|| invalid("packet too short"))
+}
+
+fn invalid(msg: &str) -> IoError {
+	IoError::new(ErrorKind::InvalidData, msg)
+}
+
+const IPV4_HDR_LEN: usize = 20;
+const IPV6_HDR_LEN: usize = 40;
+const UDP6_HDR_LEN: usize = IPV6_HDR_LEN + 8;
+const TCP_HDR_LEN: usize = 20;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_PSH: u8 = 0x08;
+const TCP_CWR: u8 = 0x80;
+
+/// Limits of a udp super packet, the kernel segments at most 64 datagrams
+const MAX_UDP_LEN: usize = u16::MAX as usize;
+const UDP_MAX_SEGMENTS: usize = 64;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn gso(gso_type: u8, gso_size: u16) -> VirtioNetHdr {
+		VirtioNetHdr {
+			gso_type,
+			gso_size,
+			..Default::default()
+		}
+	}
+
+	/// Ipv4 packet with an ihl of `ihl` words and an l4 header of `l4_len` bytes
+	fn ipv4(ihl: u8, proto: u8, l4_len: usize, payload: usize) -> Vec<u8> {
+		let mut packet = vec![0u8; ihl as usize * 4 + l4_len + payload];
+		packet[0] = 0x40 | ihl;
+		packet[9] = proto;
+		packet[12..16].copy_from_slice(&[192, 0, 2, 1]);
+		packet[16..20].copy_from_slice(&[192, 0, 2, 2]);
+		packet
+	}
+
+	/// Ipv6 udp datagram from port `port` with a complete checksum
+	fn udp6(port: u16, payload: usize, fill: u8) -> Vec<u8> {
+		let mut packet = vec![fill; UDP6_HDR_LEN + payload];
+		let udp_len = (8 + payload) as u16;
+		packet[..8].copy_from_slice(&[0x60, 0, 0, 0, 0, 0, IPPROTO_UDP, 64]);
+		packet[4..6].copy_from_slice(&udp_len.to_be_bytes());
+		packet[8..40].fill(0);
+		packet[8..10].copy_from_slice(&[0x20, 0x01]);
+		packet[23] = 1;
+		packet[24..26].copy_from_slice(&[0x20, 0x01]);
+		packet[39] = 2;
+		packet[40..42].copy_from_slice(&port.to_be_bytes());
+		packet[42..44].copy_from_slice(&53u16.to_be_bytes());
+		packet[44..46].copy_from_slice(&udp_len.to_be_bytes());
+		packet[46..48].copy_from_slice(&[0, 0]);
+		let csum = IpHeader { v4: false, len: 40 }.l4_checksum(&packet, IPPROTO_UDP, udp_len as _);
+		packet[46..48].copy_from_slice(&csum.to_be_bytes());
+		packet
+	}
+
+	fn segments(hdr: VirtioNetHdr, packet: &mut [u8]) -> IoResult<Vec<Vec<u8>>> {
+		let mut segs = Vec::new();
+		hdr.segment(packet, |seg| segs.push(seg.to_vec()))?;
+		Ok(segs)
+	}
+
+	#[test]
+	fn segment_udp() {
+		let mut packet = ipv4(5, IPPROTO_UDP, 8, 3000);
+		for (i, byte) in packet[28..].iter_mut().enumerate() {
+			*byte = i as u8;
+		}
+
+		let segs = segments(gso(VIRTIO_NET_HDR_GSO_UDP_L4, 1400), &mut packet).unwrap();
+		let lens: Vec<usize> = segs.iter().map(|seg| seg.len()).collect();
+		assert_eq!(lens, [1428, 1428, 228]);
+		for seg in &segs {
+			assert_eq!(u16::from_be_bytes([seg[2], seg[3]]) as usize, seg.len());
+			assert_eq!(fold(sum(&seg[..20], 0)), 0xffff);
+			let pseudo = sum(&seg[12..20], 0) + IPPROTO_UDP as u32 + seg.len() as u32 - 20;
+			assert_eq!(fold(sum(&seg[20..], pseudo)), 0xffff);
+		}
+		assert_eq!(segs[2][28], (2800 % 256) as u8);
+	}
+
+	#[test]
+	fn segment_short_ipv4_header() {
+		for ihl in 0..5 {
+			let mut packet = ipv4(ihl, IPPROTO_UDP, 8, 100);
+			assert!(segments(gso(VIRTIO_NET_HDR_GSO_UDP_L4, 50), &mut packet).is_err());
+		}
+	}
+
+	#[test]
+	fn segment_short_tcp_header() {
+		for off in 0..5 {
+			let mut packet = ipv4(5, IPPROTO_TCP, 20, 100);
+			packet[32] = off << 4;
+			assert!(segments(gso(VIRTIO_NET_HDR_GSO_TCPV4, 50), &mut packet).is_err());
+		}
+	}
+
+	#[test]
+	fn segment_truncated() {
+		let hdr = gso(VIRTIO_NET_HDR_GSO_TCPV4, 50);
+		assert!(segments(hdr, &mut []).is_err());
+		// data offset behind the end
+		assert!(segments(hdr, &mut ipv4(5, IPPROTO_TCP, 0, 10)).is_err());
+		// data offset larger than the packet
+		let mut packet = ipv4(5, IPPROTO_TCP, 20, 0);
+		packet[32] = 15 << 4;
+		assert!(segments(hdr, &mut packet).is_err());
+		// ipv6 header cut off
+		let mut packet = vec![0x60; 30];
+		assert!(segments(gso(VIRTIO_NET_HDR_GSO_UDP_L4, 50), &mut packet).is_err());
+	}
+
+	#[test]
+	fn segment_invalid_gso() {
+		let mut packet = ipv4(5, IPPROTO_UDP, 8, 100);
+		assert!(segments(gso(VIRTIO_NET_HDR_GSO_UDP_L4, 0), &mut packet).is_err());
+		assert!(segments(gso(3, 50), &mut packet).is_err());
+		packet[0] = 0x55;
+		assert!(segments(gso(VIRTIO_NET_HDR_GSO_UDP_L4, 50), &mut packet).is_err());
+	}
+
+	#[test]
+	fn complete_csum_out_of_bounds() {
+		let hdr = VirtioNetHdr {
+			flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+			csum_start: 20,
+			csum_offset: 6,
+			..Default::default()
+		};
+		assert!(segments(hdr, &mut ipv4(5, IPPROTO_UDP, 7, 0)).is_err());
+		assert_eq!(
+			segments(hdr, &mut ipv4(5, IPPROTO_UDP, 8, 0))
+				.unwrap()
+				.len(),
+			1
+		);
+	}
+
+	#[test]
+	fn coalesce_udp_round_trip() {
+		let packets = [
+			udp6(1000, 1200, 1),
+			udp6(1000, 1200, 2),
+			udp6(1000, 500, 3),
+			udp6(1000, 1200, 4),
+		];
+		let mut buf = Vec::new();
+		let (hdr, count) = VirtioNetHdr::coalesce_udp(&packets, &mut buf).unwrap();
+		// the short datagram ends the super packet
+		assert_eq!(count, 3);
+		assert_eq!(hdr.gso_size, 1200);
+		assert_eq!(buf.len(), UDP6_HDR_LEN + 2900);
+
+		// the checksum field holds the pseudo header sum
+		let csum = VirtioNetHdr {
+			flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+			csum_start: hdr.csum_start,
+			csum_offset: hdr.csum_offset,
+			..Default::default()
+		};
+		let mut complete = buf.clone();
+		csum.complete_csum(&mut complete).unwrap();
+		let pseudo = sum(&complete[8..40], 0) + IPPROTO_UDP as u32 + 2908;
+		assert_eq!(fold(sum(&complete[40..], pseudo)), 0xffff);
+
+		assert_eq!(segments(hdr, &mut buf).unwrap(), packets[..3]);
+	}
+
+	#[test]
+	fn coalesce_udp_flows() {
+		let mut buf = Vec::new();
+		assert!(VirtioNetHdr::coalesce_udp(&[udp6(1000, 100, 1)], &mut buf).is_none());
+		let other_port = [udp6(1000, 100, 1), udp6(1001, 100, 1)];
+		assert!(VirtioNetHdr::coalesce_udp(&other_port, &mut buf).is_none());
+		let larger = [udp6(1000, 100, 1), udp6(1000, 200, 1)];
+		assert!(VirtioNetHdr::coalesce_udp(&larger, &mut buf).is_none());
+		let empty = [udp6(1000, 0, 1), udp6(1000, 0, 1)];
+		assert!(VirtioNetHdr::coalesce_udp(&empty, &mut buf).is_none());
+		let v4 = [ipv4(5, IPPROTO_UDP, 8, 100), ipv4(5, IPPROTO_UDP, 8, 100)];
+		assert!(VirtioNetHdr::coalesce_udp(&v4, &mut buf).is_none());
+
+		let many = vec![udp6(1000, 100, 1); 70];
+		let (_, count) = VirtioNetHdr::coalesce_udp(&many, &mut buf).unwrap();
+		assert_eq!(count, UDP_MAX_SEGMENTS);
+		let large = vec![udp6(1000, 1400, 1); 60];
+		let (_, count) = VirtioNetHdr::coalesce_udp(&large, &mut buf).unwrap();
+		assert_eq!(count, (MAX_UDP_LEN - 8) / 1400);
+	}
+}